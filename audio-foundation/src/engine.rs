@@ -0,0 +1,28 @@
+pub mod analysis;
+pub mod drums;
+pub mod effects;
+pub mod envelope;
+pub mod faust;
+pub mod filter;
+pub mod fm;
+pub mod granular;
+pub mod hotswap;
+pub mod input;
+pub mod instruments;
+pub mod limiter;
+pub mod load;
+pub mod looper;
+pub mod mixer;
+pub mod modulation;
+mod noise;
+pub mod oscillator;
+pub mod output;
+pub mod params;
+pub mod patch;
+pub mod physical;
+pub mod pitch;
+pub mod preset;
+pub mod recorder;
+pub mod sampler;
+pub mod tuning;
+pub mod voice;
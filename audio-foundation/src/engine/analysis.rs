@@ -0,0 +1,203 @@
+use std::{collections::VecDeque, sync::Arc};
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+// Length of the FFT window, about 46 ms at 44.1 kHz
+const WINDOW: usize = 2048;
+// Lowest edge of the first band in Hz
+const LOWEST_BAND: f64 = 30.0;
+// Everything below this goes into the beat detector, that's where the kick lives
+const BEAT_CUTOFF: f64 = 150.0;
+// How many spectral flux values make up the running average that onsets are compared against
+const FLUX_HISTORY: usize = 30;
+// No new onset within this time after the last one
+const ONSET_HOLD: f64 = 0.1;
+
+// The audio thread end: takes the master output and never waits.
+// When the UI thread doesn't keep up, samples are dropped.
+pub struct AnalysisTap {
+    producer: Producer<f32>,
+}
+
+impl AnalysisTap {
+    pub fn push(&mut self, left: f64, right: f64) {
+        let _ = self.producer.push(((left + right) * 0.5) as f32);
+    }
+}
+
+// The features of the latest audio, updated once per frame
+#[derive(Clone, Debug, Default)]
+pub struct Analysis {
+    // Of the samples since the last update
+    pub rms: f64,
+    pub peak: f64,
+    // Magnitude of each log spaced band, roughly 0.0 to 1.0
+    pub bands: Vec<f64>,
+    // "Center of mass" of the spectrum in Hz, higher means brighter
+    pub centroid: f64,
+    // Sudden rise in the whole spectrum, and in the low end only
+    pub onset: bool,
+    pub beat: bool,
+}
+
+// Onset detection by spectral flux: how much the spectrum grew since the last frame,
+// compared to the average of the last frames
+struct OnsetDetector {
+    previous: Vec<f64>,
+    history: VecDeque<f64>,
+    // Seconds since the last onset
+    since: f64,
+}
+
+impl OnsetDetector {
+    fn new() -> Self {
+        Self {
+            previous: Vec::new(),
+            history: VecDeque::with_capacity(FLUX_HISTORY),
+            since: ONSET_HOLD,
+        }
+    }
+
+    fn detect(&mut self, magnitudes: &[f64], dt: f64) -> bool {
+        if self.previous.len() != magnitudes.len() {
+            self.previous = magnitudes.to_vec();
+        }
+        let flux: f64 = magnitudes.iter().zip(self.previous.iter()).map(|(m, p)| (m - p).max(0.0)).sum();
+        self.previous.copy_from_slice(magnitudes);
+
+        let average = if self.history.is_empty() { flux } else { self.history.iter().sum::<f64>() / self.history.len() as f64 };
+        if self.history.len() == FLUX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(flux);
+
+        self.since += dt;
+        let onset = self.since >= ONSET_HOLD && flux > average * 1.5 + 1.0e-3;
+        if onset {
+            self.since = 0.0;
+        }
+        onset
+    }
+}
+
+// The UI thread end: collects the tapped samples and computes the features
+pub struct Analyzer {
+    consumer: Consumer<f32>,
+    sample_rate: f64,
+    // The last samples, oldest first. At least WINDOW, more if window() needs a longer history.
+    samples: VecDeque<f32>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    fft_input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    hann: Vec<f32>,
+    // First FFT bin of each band plus the end of the last one
+    edges: Vec<usize>,
+    onsets: OnsetDetector,
+    beats: OnsetDetector,
+    analysis: Analysis,
+}
+
+impl Analyzer {
+    // `history` is how many samples window() returns, the FFT always looks at the last WINDOW of them
+    pub fn new(sample_rate: f64, band_count: usize, history: usize) -> (Self, AnalysisTap) {
+        // A quarter of a second is plenty, even if a few frames are dropped
+        let (producer, consumer) = RingBuffer::new((sample_rate * 0.25) as usize);
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(WINDOW);
+        let hann = (0..WINDOW)
+            .map(|i| (0.5 - 0.5 * (std::f64::consts::TAU * i as f64 / WINDOW as f64).cos()) as f32)
+            .collect();
+        let mut analyzer = Self {
+            consumer,
+            sample_rate,
+            samples: VecDeque::from(vec![0.0; history.max(WINDOW)]),
+            fft_input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            fft,
+            hann,
+            edges: Vec::new(),
+            onsets: OnsetDetector::new(),
+            beats: OnsetDetector::new(),
+            analysis: Analysis::default(),
+        };
+        analyzer.set_band_count(band_count);
+        (analyzer, AnalysisTap { producer })
+    }
+
+    // Splits LOWEST_BAND up to half the sample rate into bands of equal width in octaves.
+    // Low bands that would be narrower than one bin get one bin each.
+    pub fn set_band_count(&mut self, band_count: usize) {
+        let band_count = band_count.clamp(1, WINDOW / 4);
+        let bin_width = self.sample_rate / WINDOW as f64;
+        let nyquist = self.sample_rate * 0.5;
+        self.edges.clear();
+        let mut last = 0;
+        for band in 0..=band_count {
+            let freq = LOWEST_BAND * (nyquist / LOWEST_BAND).powf(band as f64 / band_count as f64);
+            let bin = ((freq / bin_width).round() as usize).clamp(1, WINDOW / 2);
+            // Always at least one bin, so all bands have a value
+            let bin = if band > 0 { bin.max(last + 1).min(WINDOW / 2 + 1) } else { bin };
+            self.edges.push(bin);
+            last = bin;
+        }
+        self.analysis.bands = vec![0.0; band_count];
+    }
+
+    pub fn analysis(&self) -> &Analysis {
+        &self.analysis
+    }
+
+    // The whole history as it came in, for other detectors like the pitch tracker
+    pub fn window(&mut self) -> &[f32] {
+        self.samples.make_contiguous()
+    }
+
+    // Reads everything the tap sent since the last call and updates the features
+    pub fn update(&mut self) -> &Analysis {
+        let mut count = 0;
+        let mut sum = 0.0;
+        let mut peak: f64 = 0.0;
+        while let Ok(sample) = self.consumer.pop() {
+            let value = sample as f64;
+            sum += value * value;
+            peak = peak.max(value.abs());
+            count += 1;
+            self.samples.pop_front();
+            self.samples.push_back(sample);
+        }
+        if count == 0 {
+            return &self.analysis;
+        }
+        self.analysis.rms = (sum / count as f64).sqrt();
+        self.analysis.peak = peak;
+
+        let recent = self.samples.iter().skip(self.samples.len() - WINDOW);
+        for ((input, sample), window) in self.fft_input.iter_mut().zip(recent).zip(self.hann.iter()) {
+            *input = sample * window;
+        }
+        if self.fft.process(&mut self.fft_input, &mut self.spectrum).is_err() {
+            return &self.analysis;
+        }
+        // Scaled so a full scale sine is about 1.0
+        let scale = 4.0 / WINDOW as f64;
+        let magnitudes: Vec<f64> = self.spectrum.iter().map(|bin| bin.norm() as f64 * scale).collect();
+
+        let bin_width = self.sample_rate / WINDOW as f64;
+        let total: f64 = magnitudes.iter().sum();
+        self.analysis.centroid = if total > 1.0e-9 {
+            magnitudes.iter().enumerate().map(|(i, m)| i as f64 * bin_width * m).sum::<f64>() / total
+        } else {
+            0.0
+        };
+
+        for (band, edges) in self.analysis.bands.iter_mut().zip(self.edges.windows(2)) {
+            let bins = &magnitudes[edges[0]..edges[1].min(magnitudes.len())];
+            *band = if bins.is_empty() { 0.0 } else { bins.iter().cloned().fold(0.0, f64::max) };
+        }
+
+        let dt = count as f64 / self.sample_rate;
+        self.analysis.onset = self.onsets.detect(&magnitudes, dt);
+        let beat_bins = ((BEAT_CUTOFF / bin_width).ceil() as usize).clamp(1, magnitudes.len());
+        self.analysis.beat = self.beats.detect(&magnitudes[..beat_bins], dt);
+        &self.analysis
+    }
+}
@@ -0,0 +1,368 @@
+use std::f64::consts::PI;
+use fundsp::hacker::*;
+
+use super::filter::Svf;
+use super::noise::Noise;
+use super::params::{Param, ParamRegistry};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrumKind {
+    Kick,
+    Snare,
+    ClosedHat,
+    OpenHat,
+    Clap,
+    Tom,
+}
+
+impl DrumKind {
+    pub const ALL: [DrumKind; 6] = [
+        DrumKind::Kick,
+        DrumKind::Snare,
+        DrumKind::ClosedHat,
+        DrumKind::OpenHat,
+        DrumKind::Clap,
+        DrumKind::Tom,
+    ];
+
+    // General MIDI drum notes
+    pub fn from_note(note: u8) -> Option<Self> {
+        match note {
+            35 | 36 => Some(DrumKind::Kick),
+            38 | 40 => Some(DrumKind::Snare),
+            42 | 44 => Some(DrumKind::ClosedHat),
+            46 => Some(DrumKind::OpenHat),
+            39 => Some(DrumKind::Clap),
+            41 | 43 | 45 | 47 | 48 | 50 => Some(DrumKind::Tom),
+            _ => None,
+        }
+    }
+}
+
+// Trigger for one drum, works like the voice gate: every hit writes a new number
+#[derive(Clone)]
+struct DrumTrigger {
+    count: Shared<f64>,
+    velocity: Shared<f64>,
+}
+
+// The part of the drum machine that stays on the control side, cloning it is cheap
+#[derive(Clone)]
+pub struct DrumTriggers {
+    triggers: Vec<DrumTrigger>,
+}
+
+impl DrumTriggers {
+    pub fn hit(&self, kind: DrumKind, velocity: f64) {
+        let trigger = &self.triggers[kind as usize];
+        trigger.velocity.set_value(velocity);
+        trigger.count.set_value(trigger.count.value() + 1.0);
+    }
+
+    // Hits the drums that play on this step of the pattern
+    pub fn play_step(&self, pattern: &DrumPattern, step: usize) {
+        for (kind, steps) in pattern {
+            if steps[step % PATTERN_STEPS] {
+                // Accent on the beat
+                self.hit(*kind, if step.is_multiple_of(4) { 1.0 } else { 0.7 });
+            }
+        }
+    }
+}
+
+// The drum sequencer plays this many sixteenth notes per bar
+pub const PATTERN_STEPS: usize = 16;
+
+// The steps every drum plays on
+pub type DrumPattern = Vec<(DrumKind, [bool; PATTERN_STEPS])>;
+
+// The beat the sequencer starts with
+pub fn default_pattern() -> DrumPattern {
+    let steps = |hits: &[usize]| {
+        let mut pattern = [false; PATTERN_STEPS];
+        for hit in hits {
+            pattern[*hit] = true;
+        }
+        pattern
+    };
+    vec![
+        (DrumKind::Kick, steps(&[0, 4, 8, 10, 12])),
+        (DrumKind::Snare, steps(&[4, 12])),
+        (DrumKind::ClosedHat, steps(&[0, 2, 4, 6, 8, 10, 12])),
+        (DrumKind::OpenHat, steps(&[14])),
+    ]
+}
+
+// Exponential decay that reaches -60 dB after the given time
+fn decay_coefficient(time: f64, sample_rate: f64) -> f64 {
+    (-6.9 / (time.max(0.001) * sample_rate)).exp()
+}
+
+// Sine with a falling pitch, used for the kick and the tom
+#[derive(Clone)]
+struct PitchedDrum {
+    pitch: Param,
+    sweep: Param,
+    decay: Param,
+    phase: f64,
+    amp: f64,
+    bend: f64,
+}
+
+impl PitchedDrum {
+    fn new(registry: &ParamRegistry, prefix: &str, pitch: f64, sweep: f64, decay: f64) -> Self {
+        Self {
+            pitch: registry.add(&format!("{prefix}.pitch"), 30.0, 400.0, pitch),
+            sweep: registry.add(&format!("{prefix}.sweep"), 0.0, 1000.0, sweep),
+            decay: registry.add(&format!("{prefix}.decay"), 0.02, 2.0, decay),
+            phase: 0.0,
+            amp: 0.0,
+            bend: 0.0,
+        }
+    }
+
+    fn trigger(&mut self, velocity: f64) {
+        self.phase = 0.0;
+        self.amp = velocity;
+        self.bend = 1.0;
+    }
+
+    fn tick(&mut self, sample_rate: f64) -> f64 {
+        if self.amp < 1.0e-5 {
+            return 0.0;
+        }
+        // The sweep falls much faster than the body so it sounds like a click going into a tone
+        let freq = self.pitch.value() + self.sweep.value() * self.bend;
+        self.bend *= decay_coefficient(0.04, sample_rate);
+        self.phase = (self.phase + freq / sample_rate).fract();
+        self.amp *= decay_coefficient(self.decay.value(), sample_rate);
+        (self.phase * 2.0 * PI).sin() * self.amp
+    }
+}
+
+// A short sine body with filtered noise on top
+#[derive(Clone)]
+struct Snare {
+    tone: Param,
+    snappy: Param,
+    decay: Param,
+    noise: Noise,
+    filter: Svf,
+    phase: f64,
+    body: f64,
+    snap: f64,
+}
+
+impl Snare {
+    fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            tone: registry.add(&format!("{prefix}.tone"), 100.0, 400.0, 180.0),
+            snappy: registry.add(&format!("{prefix}.snappy"), 0.0, 1.0, 0.6),
+            decay: registry.add(&format!("{prefix}.decay"), 0.05, 1.0, 0.25),
+            noise: Noise::new(0x1234_5678),
+            filter: Svf::default(),
+            phase: 0.0,
+            body: 0.0,
+            snap: 0.0,
+        }
+    }
+
+    fn trigger(&mut self, velocity: f64) {
+        self.phase = 0.0;
+        self.body = velocity;
+        self.snap = velocity;
+    }
+
+    fn tick(&mut self, sample_rate: f64) -> f64 {
+        if self.body < 1.0e-5 && self.snap < 1.0e-5 {
+            return 0.0;
+        }
+        let decay = self.decay.value();
+        self.phase = (self.phase + self.tone.value() / sample_rate).fract();
+        self.body *= decay_coefficient(decay * 0.5, sample_rate);
+        self.snap *= decay_coefficient(decay, sample_rate);
+
+        let g = Svf::coefficient(3000.0, sample_rate);
+        let (_, high, _, _) = self.filter.tick(self.noise.next(), g, 1.0);
+        let snappy = self.snappy.value();
+        (self.phase * 2.0 * PI).sin() * self.body * (1.0 - snappy) + high * self.snap * snappy
+    }
+}
+
+// Highpassed noise. The open and the closed hat are one voice so the closed hat can choke the open one.
+#[derive(Clone)]
+struct Hats {
+    tone: Param,
+    closed_decay: Param,
+    open_decay: Param,
+    noise: Noise,
+    filter: Svf,
+    amp: f64,
+    open: bool,
+}
+
+impl Hats {
+    fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            tone: registry.add(&format!("{prefix}.tone"), 2000.0, 14000.0, 7000.0),
+            closed_decay: registry.add(&format!("{prefix}.closed_decay"), 0.01, 0.3, 0.05),
+            open_decay: registry.add(&format!("{prefix}.open_decay"), 0.1, 2.0, 0.5),
+            noise: Noise::new(0x8765_4321),
+            filter: Svf::default(),
+            amp: 0.0,
+            open: false,
+        }
+    }
+
+    fn trigger(&mut self, velocity: f64, open: bool) {
+        self.amp = velocity;
+        self.open = open;
+    }
+
+    fn tick(&mut self, sample_rate: f64) -> f64 {
+        if self.amp < 1.0e-5 {
+            return 0.0;
+        }
+        let decay = if self.open { self.open_decay.value() } else { self.closed_decay.value() };
+        self.amp *= decay_coefficient(decay, sample_rate);
+        let g = Svf::coefficient(self.tone.value(), sample_rate);
+        let (_, high, _, _) = self.filter.tick(self.noise.next(), g, 1.4);
+        high * self.amp
+    }
+}
+
+// Bandpassed noise with a few quick bursts before the tail, like hands that don't clap at the same time
+#[derive(Clone)]
+struct Clap {
+    tone: Param,
+    decay: Param,
+    noise: Noise,
+    filter: Svf,
+    time: f64,
+    amp: f64,
+    velocity: f64,
+}
+
+impl Clap {
+    const BURSTS: usize = 3;
+    const SPACING: f64 = 0.011;
+
+    fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            tone: registry.add(&format!("{prefix}.tone"), 500.0, 3000.0, 1200.0),
+            decay: registry.add(&format!("{prefix}.decay"), 0.05, 1.0, 0.3),
+            noise: Noise::new(0x0bad_cafe),
+            filter: Svf::default(),
+            time: f64::MAX,
+            amp: 0.0,
+            velocity: 0.0,
+        }
+    }
+
+    fn trigger(&mut self, velocity: f64) {
+        self.time = 0.0;
+        self.amp = velocity;
+        self.velocity = velocity;
+    }
+
+    fn tick(&mut self, sample_rate: f64) -> f64 {
+        if self.amp < 1.0e-5 {
+            return 0.0;
+        }
+        let previous = self.time;
+        self.time += 1.0 / sample_rate;
+        // Restart the envelope at every burst
+        let burst = (self.time / Self::SPACING) as usize;
+        if burst < Self::BURSTS && burst != (previous / Self::SPACING) as usize {
+            self.amp = self.velocity;
+        }
+        let decay = if burst < Self::BURSTS { Self::SPACING * 0.5 } else { self.decay.value() };
+        self.amp *= decay_coefficient(decay, sample_rate);
+
+        let g = Svf::coefficient(self.tone.value(), sample_rate);
+        let (_, _, band, _) = self.filter.tick(self.noise.next(), g, 0.7);
+        band * self.amp
+    }
+}
+
+// All drum voices in one mono node. It has no inputs, the voices are played through DrumTriggers.
+#[derive(Clone)]
+pub struct DrumMachine {
+    triggers: DrumTriggers,
+    counts: [f64; DrumKind::ALL.len()],
+    kick: PitchedDrum,
+    snare: Snare,
+    hats: Hats,
+    clap: Clap,
+    tom: PitchedDrum,
+    levels: Vec<Param>,
+    sample_rate: f64,
+}
+
+impl DrumMachine {
+    // Parameters are registered as "<prefix>.kick.pitch" and so on
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        let triggers = DrumTriggers {
+            triggers: DrumKind::ALL.iter().map(|_| DrumTrigger { count: shared(0.0), velocity: shared(0.0) }).collect(),
+        };
+        let levels = ["kick", "snare", "closed_hat", "open_hat", "clap", "tom"]
+            .iter()
+            .map(|name| registry.add(&format!("{prefix}.{name}.level"), 0.0, 1.0, 0.8))
+            .collect();
+        Self {
+            triggers,
+            counts: [0.0; DrumKind::ALL.len()],
+            kick: PitchedDrum::new(registry, &format!("{prefix}.kick"), 50.0, 150.0, 0.4),
+            snare: Snare::new(registry, &format!("{prefix}.snare")),
+            hats: Hats::new(registry, &format!("{prefix}.hat")),
+            clap: Clap::new(registry, &format!("{prefix}.clap")),
+            tom: PitchedDrum::new(registry, &format!("{prefix}.tom"), 120.0, 60.0, 0.3),
+            levels,
+            sample_rate: DEFAULT_SR,
+        }
+    }
+
+    pub fn triggers(&self) -> DrumTriggers {
+        self.triggers.clone()
+    }
+}
+
+impl AudioNode for DrumMachine {
+    const ID: u64 = 0x4f58_0003;
+    type Sample = f64;
+    type Inputs = U0;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, _input: &Frame<f64, U0>) -> Frame<f64, U1> {
+        for (i, kind) in DrumKind::ALL.iter().enumerate() {
+            let trigger = &self.triggers.triggers[i];
+            let count = trigger.count.value();
+            if count == self.counts[i] {
+                continue;
+            }
+            self.counts[i] = count;
+            let velocity = trigger.velocity.value() * self.levels[i].value();
+            match kind {
+                DrumKind::Kick => self.kick.trigger(velocity),
+                DrumKind::Snare => self.snare.trigger(velocity),
+                DrumKind::ClosedHat => self.hats.trigger(velocity, false),
+                DrumKind::OpenHat => self.hats.trigger(velocity, true),
+                DrumKind::Clap => self.clap.trigger(velocity),
+                DrumKind::Tom => self.tom.trigger(velocity),
+            }
+        }
+
+        let sample_rate = self.sample_rate;
+        let output = self.kick.tick(sample_rate)
+            + self.snare.tick(sample_rate)
+            + self.hats.tick(sample_rate)
+            + self.clap.tick(sample_rate)
+            + self.tom.tick(sample_rate);
+        [output].into()
+    }
+}
@@ -0,0 +1,410 @@
+use std::f64::consts::PI;
+use fundsp::hacker::*;
+use serde::{Deserialize, Serialize};
+
+use super::params::{Param, ParamRegistry};
+
+// Circular buffer that can be read at fractional delays
+#[derive(Clone, Default)]
+pub struct DelayLine {
+    buffer: Vec<f64>,
+    position: usize,
+}
+
+impl DelayLine {
+    pub fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; std::cmp::max(length, 2)],
+            position: 0,
+        }
+    }
+
+    pub fn write(&mut self, x: f64) {
+        self.position = (self.position + 1) % self.buffer.len();
+        self.buffer[self.position] = x;
+    }
+
+    // Delay in samples, linearly interpolated. A delay of 0.0 returns the last written sample.
+    pub fn read(&self, delay: f64) -> f64 {
+        let length = self.buffer.len();
+        let delay = delay.clamp(0.0, (length - 2) as f64);
+        let whole = delay.floor();
+        let fraction = delay - whole;
+        let a = self.buffer[(self.position + length - whole as usize) % length];
+        let b = self.buffer[(self.position + length - whole as usize - 1) % length];
+        lerp(a, b, fraction)
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.iter_mut().for_each(|x| *x = 0.0);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EffectKind {
+    Drive,
+    Chorus,
+    Delay,
+    Reverb,
+}
+
+impl EffectKind {
+    pub const ALL: [EffectKind; 4] = [EffectKind::Drive, EffectKind::Chorus, EffectKind::Delay, EffectKind::Reverb];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EffectKind::Drive => "drive",
+            EffectKind::Chorus => "chorus",
+            EffectKind::Delay => "delay",
+            EffectKind::Reverb => "reverb",
+        }
+    }
+}
+
+pub type EffectOrder = [EffectKind; EffectKind::ALL.len()];
+
+// A stereo processor that only produces the wet signal, mixing and bypass are done by the chain
+pub trait Effect: Send {
+    fn set_sample_rate(&mut self, sample_rate: f64);
+    fn reset(&mut self);
+    fn process(&mut self, left: f64, right: f64) -> (f64, f64);
+}
+
+// Tanh saturation with a tone control after it
+pub struct Drive {
+    amount: Param,
+    tone: Param,
+    state: (f64, f64),
+    sample_rate: f64,
+}
+
+impl Drive {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            amount: registry.add(&format!("{prefix}.amount"), 1.0, 20.0, 3.0),
+            tone: registry.add(&format!("{prefix}.tone"), 500.0, 16000.0, 6000.0),
+            state: (0.0, 0.0),
+            sample_rate: DEFAULT_SR,
+        }
+    }
+}
+
+impl Effect for Drive {
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn reset(&mut self) {
+        self.state = (0.0, 0.0);
+    }
+
+    fn process(&mut self, left: f64, right: f64) -> (f64, f64) {
+        let amount = self.amount.value();
+        // Keep the level roughly the same when the drive goes up
+        let makeup = 1.0 / amount.tanh();
+        let g = 1.0 - (-2.0 * PI * self.tone.value() / self.sample_rate).exp();
+        self.state.0 += g * ((amount * left).tanh() * makeup - self.state.0);
+        self.state.1 += g * ((amount * right).tanh() * makeup - self.state.1);
+        self.state
+    }
+}
+
+// Chorus with short delays, with feedback and a very short delay it becomes a flanger
+pub struct Chorus {
+    rate: Param,
+    depth: Param,
+    delay: Param,
+    feedback: Param,
+    lines: (DelayLine, DelayLine),
+    phase: f64,
+    sample_rate: f64,
+}
+
+impl Chorus {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            rate: registry.add(&format!("{prefix}.rate"), 0.01, 10.0, 0.3),
+            // Delay and depth in milliseconds
+            depth: registry.add(&format!("{prefix}.depth"), 0.0, 10.0, 3.0),
+            delay: registry.add(&format!("{prefix}.delay"), 0.5, 30.0, 12.0),
+            feedback: registry.add(&format!("{prefix}.feedback"), -0.95, 0.95, 0.0),
+            lines: (DelayLine::default(), DelayLine::default()),
+            phase: 0.0,
+            sample_rate: DEFAULT_SR,
+        }
+    }
+}
+
+impl Effect for Chorus {
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        let length = (0.05 * sample_rate) as usize;
+        self.lines = (DelayLine::new(length), DelayLine::new(length));
+    }
+
+    fn reset(&mut self) {
+        self.lines.0.clear();
+        self.lines.1.clear();
+    }
+
+    fn process(&mut self, left: f64, right: f64) -> (f64, f64) {
+        self.phase = (self.phase + self.rate.value() / self.sample_rate).fract();
+        let ms = self.sample_rate / 1000.0;
+        let base = self.delay.value() * ms;
+        let depth = self.depth.value() * ms * 0.5;
+        // The right side runs a quarter cycle behind, which spreads the sound
+        let delay_left = base + depth * (1.0 + (self.phase * 2.0 * PI).sin());
+        let delay_right = base + depth * (1.0 + (self.phase * 2.0 * PI).cos());
+
+        let wet_left = self.lines.0.read(delay_left);
+        let wet_right = self.lines.1.read(delay_right);
+        let feedback = self.feedback.value();
+        self.lines.0.write(left + feedback * wet_left);
+        self.lines.1.write(right + feedback * wet_right);
+        (wet_left, wet_right)
+    }
+}
+
+// Ping pong delay, the time is given in beats and follows the tempo
+pub struct Delay {
+    time: Param,
+    feedback: Param,
+    tone: Param,
+    bpm: Shared<f64>,
+    lines: (DelayLine, DelayLine),
+    // Smoothed delay time in samples, so tempo changes glide instead of click.
+    // None until the first sample after a reset, which starts right at the target.
+    delay: Option<f64>,
+    damping: (f64, f64),
+    sample_rate: f64,
+}
+
+impl Delay {
+    const MAX_TIME: f64 = 4.0;
+
+    pub fn new(registry: &ParamRegistry, prefix: &str, bpm: &Shared<f64>) -> Self {
+        Self {
+            time: registry.add(&format!("{prefix}.time"), 0.0625, 4.0, 0.75),
+            feedback: registry.add(&format!("{prefix}.feedback"), 0.0, 0.95, 0.4),
+            tone: registry.add(&format!("{prefix}.tone"), 500.0, 16000.0, 4000.0),
+            bpm: bpm.clone(),
+            lines: (DelayLine::default(), DelayLine::default()),
+            delay: None,
+            damping: (0.0, 0.0),
+            sample_rate: DEFAULT_SR,
+        }
+    }
+}
+
+impl Effect for Delay {
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        let length = (Self::MAX_TIME * sample_rate) as usize + 2;
+        self.lines = (DelayLine::new(length), DelayLine::new(length));
+        self.delay = None;
+    }
+
+    fn reset(&mut self) {
+        self.lines.0.clear();
+        self.lines.1.clear();
+        self.delay = None;
+        self.damping = (0.0, 0.0);
+    }
+
+    fn process(&mut self, left: f64, right: f64) -> (f64, f64) {
+        let seconds = self.time.value() * 60.0 / self.bpm.value().max(1.0);
+        let target = seconds.min(Self::MAX_TIME) * self.sample_rate;
+        let delay = self.delay.map_or(target, |delay| delay + (target - delay) * 0.0005);
+        self.delay = Some(delay);
+
+        let wet_left = self.lines.0.read(delay);
+        let wet_right = self.lines.1.read(delay);
+        let g = 1.0 - (-2.0 * PI * self.tone.value() / self.sample_rate).exp();
+        self.damping.0 += g * (wet_left - self.damping.0);
+        self.damping.1 += g * (wet_right - self.damping.1);
+
+        // The input enters on the left and the repeats bounce between the sides
+        let feedback = self.feedback.value();
+        self.lines.0.write(0.5 * (left + right) + feedback * self.damping.1);
+        self.lines.1.write(feedback * self.damping.0);
+        (wet_left, wet_right)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Comb {
+    line: DelayLine,
+    length: f64,
+    store: f64,
+}
+
+impl Comb {
+    fn process(&mut self, x: f64, feedback: f64, damping: f64) -> f64 {
+        let output = self.line.read(self.length);
+        self.store = output * (1.0 - damping) + self.store * damping;
+        self.line.write(x + self.store * feedback);
+        output
+    }
+}
+
+#[derive(Clone, Default)]
+struct Allpass {
+    line: DelayLine,
+    length: f64,
+}
+
+impl Allpass {
+    fn process(&mut self, x: f64) -> f64 {
+        let delayed = self.line.read(self.length);
+        self.line.write(x + delayed * 0.5);
+        delayed - x
+    }
+}
+
+// Freeverb style reverb with eight combs and four allpasses per side
+pub struct Reverb {
+    size: Param,
+    damping: Param,
+    combs: Vec<(Comb, Comb)>,
+    allpasses: Vec<(Allpass, Allpass)>,
+}
+
+impl Reverb {
+    // Tunings in samples at 44.1 kHz, the right side is a little longer for width
+    const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+    const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+    const SPREAD: usize = 23;
+
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            size: registry.add(&format!("{prefix}.size"), 0.0, 1.0, 0.7),
+            damping: registry.add(&format!("{prefix}.damping"), 0.0, 1.0, 0.5),
+            combs: Vec::new(),
+            allpasses: Vec::new(),
+        }
+    }
+}
+
+impl Effect for Reverb {
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        let scale = sample_rate / 44100.0;
+        let line = |length: usize| {
+            let length = length as f64 * scale;
+            (DelayLine::new(length as usize + 2), length)
+        };
+        self.combs = Self::COMBS.iter().map(|&length| {
+            let (left, left_length) = line(length);
+            let (right, right_length) = line(length + Self::SPREAD);
+            (Comb { line: left, length: left_length, store: 0.0 }, Comb { line: right, length: right_length, store: 0.0 })
+        }).collect();
+        self.allpasses = Self::ALLPASSES.iter().map(|&length| {
+            let (left, left_length) = line(length);
+            let (right, right_length) = line(length + Self::SPREAD);
+            (Allpass { line: left, length: left_length }, Allpass { line: right, length: right_length })
+        }).collect();
+    }
+
+    fn reset(&mut self) {
+        for (left, right) in self.combs.iter_mut() {
+            left.line.clear();
+            right.line.clear();
+        }
+        for (left, right) in self.allpasses.iter_mut() {
+            left.line.clear();
+            right.line.clear();
+        }
+    }
+
+    fn process(&mut self, left: f64, right: f64) -> (f64, f64) {
+        let feedback = 0.7 + 0.28 * self.size.value();
+        let damping = 0.4 * self.damping.value();
+        let input = (left + right) * 0.015;
+
+        let mut output = (0.0, 0.0);
+        for (comb_left, comb_right) in self.combs.iter_mut() {
+            output.0 += comb_left.process(input, feedback, damping);
+            output.1 += comb_right.process(input, feedback, damping);
+        }
+        for (allpass_left, allpass_right) in self.allpasses.iter_mut() {
+            output.0 = allpass_left.process(output.0);
+            output.1 = allpass_right.process(output.1);
+        }
+        output
+    }
+}
+
+// One effect in the chain with its wet/dry mix and bypass switch ("fx.delay.mix", "fx.delay.bypass")
+struct EffectSlot {
+    kind: EffectKind,
+    effect: Box<dyn Effect>,
+    mix: Param,
+    bypass: Param,
+    // Mix amount that follows the parameters smoothly so bypassing doesn't click
+    amount: f64,
+    idle: bool,
+}
+
+impl EffectSlot {
+    fn process(&mut self, left: f64, right: f64) -> (f64, f64) {
+        let target = if self.bypass.value() >= 0.5 { 0.0 } else { self.mix.value() };
+        self.amount += (target - self.amount) * 0.002;
+        if self.amount < 1.0e-6 && target == 0.0 {
+            // Fully bypassed, nothing to compute. Clear the tails so they don't come back when it's switched on.
+            if !self.idle {
+                self.effect.reset();
+                self.idle = true;
+            }
+            return (left, right);
+        }
+        self.idle = false;
+        let (wet_left, wet_right) = self.effect.process(left, right);
+        (lerp(left, wet_left, self.amount), lerp(right, wet_right, self.amount))
+    }
+}
+
+// The master effects. Every effect always exists, the order decides how they are chained.
+pub struct EffectsChain {
+    slots: Vec<EffectSlot>,
+}
+
+impl EffectsChain {
+    pub fn new(registry: &ParamRegistry, prefix: &str, bpm: &Shared<f64>) -> Self {
+        let slots = EffectKind::ALL.iter().map(|&kind| {
+            let name = format!("{prefix}.{}", kind.name());
+            let effect: Box<dyn Effect> = match kind {
+                EffectKind::Drive => Box::new(Drive::new(registry, &name)),
+                EffectKind::Chorus => Box::new(Chorus::new(registry, &name)),
+                EffectKind::Delay => Box::new(Delay::new(registry, &name, bpm)),
+                EffectKind::Reverb => Box::new(Reverb::new(registry, &name)),
+            };
+            let mix = registry.add(&format!("{name}.mix"), 0.0, 1.0, 0.3);
+            let bypass = registry.add(&format!("{name}.bypass"), 0.0, 1.0, 1.0);
+            EffectSlot { kind, effect, mix, bypass, amount: 0.0, idle: false }
+        }).collect();
+        Self { slots }
+    }
+
+    // Allocates the delay lines, so call this before the chain is moved to the audio thread
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        for slot in self.slots.iter_mut() {
+            slot.effect.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn order(&self) -> EffectOrder {
+        let mut order = EffectKind::ALL;
+        for (kind, slot) in order.iter_mut().zip(self.slots.iter()) {
+            *kind = slot.kind;
+        }
+        order
+    }
+
+    // Effects missing from the order go to the end, the unstable sort doesn't allocate on the audio thread
+    pub fn set_order(&mut self, order: &EffectOrder) {
+        self.slots.sort_unstable_by_key(|slot| order.iter().position(|kind| *kind == slot.kind).unwrap_or(order.len()));
+    }
+
+    pub fn process(&mut self, left: f64, right: f64) -> (f64, f64) {
+        self.slots.iter_mut().fold((left, right), |(l, r), slot| slot.process(l, r))
+    }
+}
@@ -0,0 +1,203 @@
+use fundsp::hacker::*;
+
+use super::params::{Param, ParamRegistry};
+
+// Time constants per segment at curve 1.0, a plain RC segment gets 99% of the way in about 5
+const MAX_STEEPNESS: f64 = 5.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Stage {
+    #[default]
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// Gate driven ADSR envelope.
+// Times are in seconds, sustain is a level from 0.0 to 1.0.
+// The curve bends every segment from linear (0.0) to exponential (1.0), like a capacitor charging through
+// a resistor: fast at first, then slowly settling on the target.
+#[derive(Clone, Debug, Default)]
+pub struct Adsr {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+    pub curve: f64,
+    stage: Stage,
+    // How far we are into the current segment from 0.0 to 1.0
+    progress: f64,
+    // Level at the start of the current segment, so retriggers and early releases don't jump
+    start: f64,
+    level: f64,
+    gate: bool,
+}
+
+impl Adsr {
+    // Opening the gate starts the attack from the current level, closing it starts the release
+    pub fn set_gate(&mut self, gate: bool) {
+        if gate && !self.gate {
+            self.retrigger();
+        }
+        if !gate && self.gate {
+            self.enter(Stage::Release);
+        }
+        self.gate = gate;
+    }
+
+    // Restart the attack even if the gate is already open
+    pub fn retrigger(&mut self) {
+        self.gate = true;
+        self.enter(Stage::Attack);
+    }
+
+    // True once the release has finished, or before the first note
+    pub fn is_idle(&self) -> bool {
+        self.stage == Stage::Idle
+    }
+
+    fn enter(&mut self, stage: Stage) {
+        self.stage = stage;
+        self.progress = 0.0;
+        self.start = self.level;
+    }
+
+    // An RC segment, scaled so it still reaches the target when the time is up
+    fn shape(&self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        let steepness = self.curve.clamp(0.0, 1.0) * MAX_STEEPNESS;
+        if steepness < 1.0e-3 {
+            return progress;
+        }
+        (1.0 - (-steepness * progress).exp()) / (1.0 - (-steepness).exp())
+    }
+
+    // Advance the envelope by dt seconds and return the new level
+    pub fn next(&mut self, dt: f64) -> f64 {
+        let sustain = self.sustain.clamp(0.0, 1.0);
+        match self.stage {
+            Stage::Idle => self.level = 0.0,
+            Stage::Attack => {
+                self.progress += dt / self.attack.max(1.0e-4);
+                self.level = lerp(self.start, 1.0, self.shape(self.progress));
+                if self.progress >= 1.0 {
+                    self.enter(Stage::Decay);
+                }
+            },
+            Stage::Decay => {
+                self.progress += dt / self.decay.max(1.0e-4);
+                self.level = lerp(self.start, sustain, self.shape(self.progress));
+                if self.progress >= 1.0 {
+                    self.enter(Stage::Sustain);
+                }
+            },
+            Stage::Sustain => self.level = sustain,
+            Stage::Release => {
+                self.progress += dt / self.release.max(1.0e-4);
+                self.level = lerp(self.start, 0.0, self.shape(self.progress));
+                if self.progress >= 1.0 {
+                    self.enter(Stage::Idle);
+                }
+            },
+        }
+        self.level
+    }
+}
+
+// The registered parameters of one envelope, for example "amp_env.attack"
+#[derive(Clone)]
+pub struct AdsrParams {
+    pub attack: Param,
+    pub decay: Param,
+    pub sustain: Param,
+    pub release: Param,
+    pub curve: Param,
+    // How much the note velocity scales the envelope, 0.0 ignores velocity
+    pub velocity: Param,
+}
+
+impl AdsrParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str, attack: f64, decay: f64, sustain: f64, release: f64) -> Self {
+        Self {
+            attack: registry.add(&format!("{prefix}.attack"), 0.001, 10.0, attack),
+            decay: registry.add(&format!("{prefix}.decay"), 0.001, 10.0, decay),
+            sustain: registry.add(&format!("{prefix}.sustain"), 0.0, 1.0, sustain),
+            release: registry.add(&format!("{prefix}.release"), 0.001, 10.0, release),
+            curve: registry.add(&format!("{prefix}.curve"), 0.0, 1.0, 0.0),
+            velocity: registry.add(&format!("{prefix}.velocity"), 0.0, 1.0, 0.5),
+        }
+    }
+
+    pub fn update(&self, adsr: &mut Adsr) {
+        adsr.attack = self.attack.value();
+        adsr.decay = self.decay.value();
+        adsr.sustain = self.sustain.value();
+        adsr.release = self.release.value();
+        adsr.curve = self.curve.value();
+    }
+
+    pub fn velocity_scale(&self, velocity: f64) -> f64 {
+        let amount = self.velocity.value();
+        1.0 - amount + amount * velocity.clamp(0.0, 1.0)
+    }
+}
+
+// ADSR node whose stages follow the parameters while it plays.
+// - Input 0: gate. Zero closes the gate, any new positive value (re)triggers the attack.
+// - Input 1: velocity from 0.0 to 1.0.
+// - Output 0: envelope.
+#[derive(Clone)]
+pub struct LiveAdsr {
+    adsr: Adsr,
+    params: AdsrParams,
+    last_gate: f64,
+    sample_duration: f64,
+}
+
+impl LiveAdsr {
+    pub fn new(params: &AdsrParams) -> Self {
+        Self {
+            adsr: Adsr::default(),
+            params: params.clone(),
+            last_gate: 0.0,
+            sample_duration: 1.0 / DEFAULT_SR,
+        }
+    }
+}
+
+impl AudioNode for LiveAdsr {
+    const ID: u64 = 0x4f58_0001;
+    type Sample = f64;
+    type Inputs = U2;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.adsr = Adsr::default();
+        self.last_gate = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_duration = 1.0 / sample_rate;
+    }
+
+    fn tick(&mut self, input: &Frame<f64, U2>) -> Frame<f64, U1> {
+        let gate = input[0];
+        if gate > 0.0 && gate != self.last_gate {
+            self.adsr.retrigger();
+        } else if gate <= 0.0 {
+            self.adsr.set_gate(false);
+        }
+        self.last_gate = gate;
+
+        self.params.update(&mut self.adsr);
+        let level = self.adsr.next(self.sample_duration);
+        [level * self.params.velocity_scale(input[1])].into()
+    }
+}
+
+pub fn live_adsr(params: &AdsrParams) -> An<LiveAdsr> {
+    An(LiveAdsr::new(params))
+}
@@ -0,0 +1,277 @@
+use std::error::Error;
+use fundsp::hacker::*;
+
+use super::params::{Param, ParamRegistry};
+
+pub mod tremolo;
+
+// The interface the Rust backend of the FAUST compiler generates code for, the same as in the
+// faust-types crate. Generated files start with `use faust_types::*;`, replace that with
+// `use super::*;` when putting them next to this file, and compile them with -double.
+// A program only uses the parts of the interface it needs, the rest has to be there anyway.
+pub type F64 = f64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ParamIndex(pub i32);
+
+pub trait Meta {
+    fn declare(&mut self, key: &str, value: &str);
+}
+
+#[allow(dead_code)]
+pub trait UI<T> {
+    fn open_tab_box(&mut self, label: &str);
+    fn open_horizontal_box(&mut self, label: &str);
+    fn open_vertical_box(&mut self, label: &str);
+    fn close_box(&mut self);
+    fn add_button(&mut self, label: &str, param: ParamIndex);
+    fn add_check_button(&mut self, label: &str, param: ParamIndex);
+    fn add_vertical_slider(&mut self, label: &str, param: ParamIndex, init: T, min: T, max: T, step: T);
+    fn add_horizontal_slider(&mut self, label: &str, param: ParamIndex, init: T, min: T, max: T, step: T);
+    fn add_num_entry(&mut self, label: &str, param: ParamIndex, init: T, min: T, max: T, step: T);
+    fn add_horizontal_bargraph(&mut self, label: &str, param: ParamIndex, min: T, max: T);
+    fn add_vertical_bargraph(&mut self, label: &str, param: ParamIndex, min: T, max: T);
+    fn declare(&mut self, param: Option<ParamIndex>, key: &str, value: &str);
+}
+
+#[allow(dead_code)]
+pub trait FaustDsp {
+    type T;
+
+    fn new() -> Self where Self: Sized;
+    fn metadata(&self, m: &mut dyn Meta);
+    fn get_sample_rate(&self) -> i32;
+    fn get_num_inputs(&self) -> i32;
+    fn get_num_outputs(&self) -> i32;
+    fn class_init(sample_rate: i32) where Self: Sized;
+    fn instance_reset_params(&mut self);
+    fn instance_clear(&mut self);
+    fn instance_constants(&mut self, sample_rate: i32);
+    fn instance_init(&mut self, sample_rate: i32);
+    fn init(&mut self, sample_rate: i32);
+    fn build_user_interface(&self, ui_interface: &mut dyn UI<Self::T>);
+    fn build_user_interface_static(ui_interface: &mut dyn UI<Self::T>) where Self: Sized;
+    fn get_param(&self, param: ParamIndex) -> Option<Self::T>;
+    fn set_param(&mut self, param: ParamIndex, value: Self::T);
+    fn compute(&mut self, count: i32, inputs: &[&[Self::T]], outputs: &mut [&mut [Self::T]]);
+}
+
+// Most inputs or outputs a FAUST program can have here, so tick doesn't have to allocate
+const MAX_CHANNELS: usize = 8;
+
+// Registers every slider, number entry, button and check box of a FAUST program as a parameter.
+// Names are the path through the boxes, without the outer box that has the name of the program:
+// "freq" in a box "filter" of a node with prefix "wah" becomes "wah.filter.freq".
+// Bargraphs are outputs of the program and are left out.
+struct ParamCollector<'a> {
+    registry: &'a ParamRegistry,
+    path: Vec<String>,
+    params: Vec<(ParamIndex, Param)>,
+}
+
+impl ParamCollector<'_> {
+    fn add(&mut self, label: &str, param: ParamIndex, init: f64, min: f64, max: f64) {
+        let mut name = vec![self.path[0].clone()];
+        name.extend(self.path.iter().skip(2).cloned());
+        name.push(param_name(label));
+        let param = (param, self.registry.add(&name.join("."), min, max, init));
+        self.params.push(param);
+    }
+
+    fn open_box(&mut self, label: &str) {
+        self.path.push(param_name(label));
+    }
+}
+
+// "Cutoff Freq" to "cutoff_freq", like the names of our own parameters
+fn param_name(label: &str) -> String {
+    label.trim().chars().map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+impl UI<f64> for ParamCollector<'_> {
+    fn open_tab_box(&mut self, label: &str) {
+        self.open_box(label);
+    }
+
+    fn open_horizontal_box(&mut self, label: &str) {
+        self.open_box(label);
+    }
+
+    fn open_vertical_box(&mut self, label: &str) {
+        self.open_box(label);
+    }
+
+    fn close_box(&mut self) {
+        // The prefix stays
+        if self.path.len() > 1 {
+            self.path.pop();
+        }
+    }
+
+    fn add_button(&mut self, label: &str, param: ParamIndex) {
+        self.add(label, param, 0.0, 0.0, 1.0);
+    }
+
+    fn add_check_button(&mut self, label: &str, param: ParamIndex) {
+        self.add(label, param, 0.0, 0.0, 1.0);
+    }
+
+    fn add_vertical_slider(&mut self, label: &str, param: ParamIndex, init: f64, min: f64, max: f64, _step: f64) {
+        self.add(label, param, init, min, max);
+    }
+
+    fn add_horizontal_slider(&mut self, label: &str, param: ParamIndex, init: f64, min: f64, max: f64, _step: f64) {
+        self.add(label, param, init, min, max);
+    }
+
+    fn add_num_entry(&mut self, label: &str, param: ParamIndex, init: f64, min: f64, max: f64, _step: f64) {
+        self.add(label, param, init, min, max);
+    }
+
+    fn add_horizontal_bargraph(&mut self, _label: &str, _param: ParamIndex, _min: f64, _max: f64) {}
+
+    fn add_vertical_bargraph(&mut self, _label: &str, _param: ParamIndex, _min: f64, _max: f64) {}
+
+    fn declare(&mut self, _param: Option<ParamIndex>, _key: &str, _value: &str) {}
+}
+
+// A program compiled by FAUST as a unit in the audio graph, with its controls in the parameter registry.
+// The inputs and outputs are the ones of the program.
+pub struct FaustNode<D> {
+    dsp: D,
+    params: Vec<(ParamIndex, Param)>,
+    sample_rate: f64,
+}
+
+impl<D: FaustDsp<T = f64> + Send + Sync + 'static> FaustNode<D> {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Result<Self, Box<dyn Error>> {
+        let mut dsp = D::new();
+        dsp.init(DEFAULT_SR as i32);
+        if dsp.get_num_inputs() as usize > MAX_CHANNELS || dsp.get_num_outputs() as usize > MAX_CHANNELS {
+            return Err(format!("FAUST programs can have at most {MAX_CHANNELS} inputs and outputs.").into());
+        }
+        let mut collector = ParamCollector { registry, path: vec![prefix.to_string()], params: Vec::new() };
+        dsp.build_user_interface(&mut collector);
+        let params = collector.params;
+        Ok(Self { dsp, params, sample_rate: DEFAULT_SR })
+    }
+
+    fn update_params(&mut self) {
+        for (index, param) in &self.params {
+            self.dsp.set_param(*index, param.value());
+        }
+    }
+}
+
+// FAUST code can't be cloned, the clone is a new instance that reads the same parameters
+impl<D: FaustDsp<T = f64>> Clone for FaustNode<D> {
+    fn clone(&self) -> Self {
+        let mut dsp = D::new();
+        dsp.init(self.sample_rate as i32);
+        Self { dsp, params: self.params.clone(), sample_rate: self.sample_rate }
+    }
+}
+
+impl<D: FaustDsp<T = f64> + Send + Sync + 'static> AudioUnit64 for FaustNode<D> {
+    fn reset(&mut self) {
+        self.dsp.instance_clear();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            // This also sets the controls back to their defaults, ours are copied in again before the next sample
+            self.dsp.init(sample_rate as i32);
+        }
+    }
+
+    fn tick(&mut self, input: &[f64], output: &mut [f64]) {
+        self.update_params();
+        // One sample long buffers for every channel
+        let mut inputs = input.chunks(1);
+        let inputs: [&[f64]; MAX_CHANNELS] = std::array::from_fn(|_| inputs.next().unwrap_or_default());
+        let mut outputs = output.chunks_mut(1);
+        let mut outputs: [&mut [f64]; MAX_CHANNELS] = std::array::from_fn(|_| outputs.next().unwrap_or_default());
+        self.dsp.compute(1, &inputs[..self.inputs()], &mut outputs[..self.dsp.get_num_outputs() as usize]);
+    }
+
+    fn process(&mut self, size: usize, input: &[&[f64]], output: &mut [&mut [f64]]) {
+        self.update_params();
+        self.dsp.compute(size as i32, input, output);
+    }
+
+    fn inputs(&self) -> usize {
+        self.dsp.get_num_inputs() as usize
+    }
+
+    fn outputs(&self) -> usize {
+        self.dsp.get_num_outputs() as usize
+    }
+
+    fn route(&mut self, _input: &SignalFrame, _frequency: f64) -> SignalFrame {
+        // Nothing is known about what the program does
+        new_signal_frame(self.outputs())
+    }
+
+    fn get_id(&self) -> u64 {
+        0x4f58_000b
+    }
+
+    fn footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::tremolo::Tremolo;
+
+    fn tremolo() -> (ParamRegistry, FaustNode<Tremolo>) {
+        let registry = ParamRegistry::new();
+        let node = FaustNode::<Tremolo>::new(&registry, "pad.tremolo").unwrap();
+        (registry, node)
+    }
+
+    #[test]
+    fn sliders_become_params() {
+        let (registry, node) = tremolo();
+        assert_eq!(node.params.len(), 2);
+        // The ranges and defaults of tremolo.dsp
+        let depth = registry.get("pad.tremolo.depth").unwrap();
+        assert_eq!(depth.range(), (0.0, 1.0));
+        assert_eq!(depth.default_value(), 0.3);
+        let rate = registry.get("pad.tremolo.rate").unwrap();
+        assert_eq!(rate.range(), (0.1, 20.0));
+        assert_eq!(rate.default_value(), 4.0);
+    }
+
+    #[test]
+    fn depth_sets_the_gain() {
+        let (registry, mut node) = tremolo();
+        registry.get("pad.tremolo.rate").unwrap().set(10.0);
+        let depth = registry.get("pad.tremolo.depth").unwrap();
+        let mut output = [0.0; 2];
+
+        depth.set(0.0);
+        node.reset();
+        for _ in 0..DEFAULT_SR as usize {
+            node.tick(&[0.5, -0.25], &mut output);
+            assert!((output[0] - 0.5).abs() < 1.0e-9 && (output[1] + 0.25).abs() < 1.0e-9);
+        }
+
+        // Full depth swings the gain between 0 and 1, once the smoothing of the depth has settled
+        depth.set(1.0);
+        let gains: Vec<f64> = (0..DEFAULT_SR as usize)
+            .map(|_| {
+                node.tick(&[1.0, 1.0], &mut output);
+                assert_eq!(output[0], output[1]);
+                output[0]
+            })
+            .skip(DEFAULT_SR as usize / 2)
+            .collect();
+        let lowest = gains.iter().copied().fold(f64::INFINITY, f64::min);
+        let highest = gains.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        assert!(lowest < 0.01 && highest > 0.99, "gain went from {lowest} to {highest}");
+    }
+}
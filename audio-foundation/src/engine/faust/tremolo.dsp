@@ -0,0 +1,17 @@
+declare name "tremolo";
+declare author "Audio Foundation";
+declare version "1.0";
+
+// Stereo tremolo, the sample FAUST program of the engine.
+// Rebuild tremolo.rs after changing this with
+//   faust -lang rust -double -cn Tremolo tremolo.dsp -o tremolo.rs
+// and replace `use faust_types::*;` in the output with `use super::*;`.
+
+import("stdfaust.lib");
+
+rate = hslider("rate [unit:Hz]", 4, 0.1, 20, 0.01);
+depth = hslider("depth", 0.3, 0, 1, 0.01) : si.smoo;
+
+gain = 1 - depth * (0.5 + 0.5 * sin(2 * ma.PI * os.phasor(1, rate)));
+
+process = *(gain), *(gain);
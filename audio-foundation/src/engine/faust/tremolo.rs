@@ -0,0 +1,158 @@
+/* ------------------------------------------------------------
+author: "Audio Foundation"
+name: "tremolo"
+version: "1.0"
+Code generated with Faust 2.70.3 (https://faust.grame.fr)
+Compilation options: -lang rust -ct 1 -cn Tremolo -es 1 -mcd 16 -mdd 1024 -mdy 33 -double -ftz 0
+------------------------------------------------------------ */
+
+#![allow(clippy::all)]
+#![allow(unused_parens)]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![allow(dead_code)]
+#![allow(unused_variables)]
+#![allow(unused_mut)]
+#![allow(non_upper_case_globals)]
+
+use super::*;
+
+pub struct Tremolo {
+	fSampleRate: i32,
+	fConst0: F64,
+	fHslider0: F64,
+	fRec0: [F64;2],
+	fConst1: F64,
+	fHslider1: F64,
+	fConst2: F64,
+	fRec1: [F64;2],
+}
+
+impl FaustDsp for Tremolo {
+	type T = F64;
+
+	fn new() -> Tremolo {
+		Tremolo {
+			fSampleRate: 0,
+			fConst0: 0.0,
+			fHslider0: 0.0,
+			fRec0: [0.0;2],
+			fConst1: 0.0,
+			fHslider1: 0.0,
+			fConst2: 0.0,
+			fRec1: [0.0;2],
+		}
+	}
+	fn metadata(&self, m: &mut dyn Meta) {
+		m.declare("author", r"Audio Foundation");
+		m.declare("basics.lib/name", r"Faust Basic Element Library");
+		m.declare("compile_options", r"-lang rust -ct 1 -cn Tremolo -es 1 -mcd 16 -mdd 1024 -mdy 33 -double -ftz 0");
+		m.declare("filename", r"tremolo.dsp");
+		m.declare("maths.lib/name", r"Faust Math Library");
+		m.declare("name", r"tremolo");
+		m.declare("oscillators.lib/name", r"Faust Oscillator Library");
+		m.declare("platform.lib/name", r"Generic Platform Library");
+		m.declare("signals.lib/name", r"Faust Signal Routing Library");
+		m.declare("version", r"1.0");
+	}
+
+	fn get_sample_rate(&self) -> i32 {
+		return self.fSampleRate;
+	}
+	fn get_num_inputs(&self) -> i32 {
+		return 2;
+	}
+	fn get_num_outputs(&self) -> i32 {
+		return 2;
+	}
+
+	fn class_init(sample_rate: i32) {
+	}
+	fn instance_reset_params(&mut self) {
+		self.fHslider0 = 4.0;
+		self.fHslider1 = 0.3;
+	}
+	fn instance_clear(&mut self) {
+		for l0 in 0..2 {
+			self.fRec0[l0 as usize] = 0.0;
+		}
+		for l1 in 0..2 {
+			self.fRec1[l1 as usize] = 0.0;
+		}
+	}
+	fn instance_constants(&mut self, sample_rate: i32) {
+		self.fSampleRate = sample_rate;
+		let mut fConst3: F64 = F64::min(1.92e+05, F64::max(1.0, ((self.fSampleRate) as F64)));
+		self.fConst0 = 1.0 / fConst3;
+		self.fConst1 = F64::exp(-(2e+02 / fConst3));
+		self.fConst2 = 1.0 - self.fConst1;
+	}
+	fn instance_init(&mut self, sample_rate: i32) {
+		self.instance_constants(sample_rate);
+		self.instance_reset_params();
+		self.instance_clear();
+	}
+	fn init(&mut self, sample_rate: i32) {
+		Tremolo::class_init(sample_rate);
+		self.instance_init(sample_rate);
+	}
+
+	fn build_user_interface(&self, ui_interface: &mut dyn UI<Self::T>) {
+		Self::build_user_interface_static(ui_interface);
+	}
+
+	fn build_user_interface_static(ui_interface: &mut dyn UI<Self::T>) {
+		ui_interface.open_vertical_box("tremolo");
+		ui_interface.add_horizontal_slider("depth", ParamIndex(0), 0.3, 0.0, 1.0, 0.01);
+		ui_interface.declare(Some(ParamIndex(1)), "unit", "Hz");
+		ui_interface.add_horizontal_slider("rate", ParamIndex(1), 4.0, 0.1, 2e+01, 0.01);
+		ui_interface.close_box();
+	}
+
+	fn get_param(&self, param: ParamIndex) -> Option<Self::T> {
+		match param.0 {
+			0 => Some(self.fHslider1),
+			1 => Some(self.fHslider0),
+			_ => None,
+		}
+	}
+
+	fn set_param(&mut self, param: ParamIndex, value: Self::T) {
+		match param.0 {
+			0 => { self.fHslider1 = value }
+			1 => { self.fHslider0 = value }
+			_ => {}
+		}
+	}
+
+	fn compute(&mut self, count: i32, inputs: &[&[Self::T]], outputs: &mut[&mut[Self::T]]) {
+		let (inputs0, inputs1) = if let [inputs0, inputs1, ..] = inputs {
+			let inputs0 = inputs0[..count as usize].iter();
+			let inputs1 = inputs1[..count as usize].iter();
+			(inputs0, inputs1)
+		} else {
+			panic!("wrong number of inputs");
+		};
+		let (outputs0, outputs1) = if let [outputs0, outputs1, ..] = outputs {
+			let outputs0 = outputs0[..count as usize].iter_mut();
+			let outputs1 = outputs1[..count as usize].iter_mut();
+			(outputs0, outputs1)
+		} else {
+			panic!("wrong number of outputs");
+		};
+		let mut fSlow0: F64 = self.fConst0 * self.fHslider0;
+		let mut fSlow1: F64 = self.fConst2 * self.fHslider1;
+		let zipped_iterators = inputs0.zip(inputs1).zip(outputs0).zip(outputs1);
+		for (((input0, input1), output0), output1) in zipped_iterators {
+			let mut fTemp0: F64 = fSlow0 + self.fRec0[1];
+			self.fRec0[0] = fTemp0 - F64::floor(fTemp0);
+			self.fRec1[0] = fSlow1 + self.fConst1 * self.fRec1[1];
+			let mut fTemp1: F64 = 1.0 - self.fRec1[0] * (0.5 * F64::sin(6.283185307179586 * self.fRec0[0]) + 0.5);
+			*output0 = ((*input0) * fTemp1) as F64;
+			*output1 = ((*input1) * fTemp1) as F64;
+			self.fRec0[1] = self.fRec0[0];
+			self.fRec1[1] = self.fRec1[0];
+		}
+	}
+
+}
@@ -0,0 +1,200 @@
+use std::f64::consts::PI;
+use fundsp::hacker::*;
+
+use super::params::{Param, ParamRegistry};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FilterMode {
+    #[default]
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+    Ladder,
+}
+
+impl FilterMode {
+    pub const ALL: [FilterMode; 5] = [
+        FilterMode::Lowpass,
+        FilterMode::Highpass,
+        FilterMode::Bandpass,
+        FilterMode::Notch,
+        FilterMode::Ladder,
+    ];
+
+    // The mode is stored as a parameter so it can be switched from MIDI and saved in presets
+    pub fn from_value(value: f64) -> Self {
+        let index = value.round().clamp(0.0, (Self::ALL.len() - 1) as f64);
+        Self::ALL[index as usize]
+    }
+}
+
+#[derive(Clone)]
+pub struct FilterParams {
+    pub cutoff: Param,
+    pub mode: Param,
+    // 0.0 is a gentle slope, 1.0 is close to self oscillation
+    pub resonance: Param,
+    // Input gain of the ladder, which saturates like the analog original
+    pub drive: Param,
+    // 0.0 keeps the cutoff fixed, 1.0 moves it one octave up for each octave played
+    pub keytrack: Param,
+}
+
+impl FilterParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            cutoff: registry.add(&format!("{prefix}.cutoff"), 100.0, 4000.0, 800.0),
+            mode: registry.add(&format!("{prefix}.type"), 0.0, (FilterMode::ALL.len() - 1) as f64, 0.0),
+            resonance: registry.add(&format!("{prefix}.resonance"), 0.0, 1.0, 0.3),
+            drive: registry.add(&format!("{prefix}.drive"), 1.0, 10.0, 1.0),
+            keytrack: registry.add(&format!("{prefix}.keytrack"), 0.0, 1.0, 0.0),
+        }
+    }
+}
+
+// Zero delay feedback state variable filter (after Andrew Simper), one run gives all four outputs
+#[derive(Clone, Default)]
+pub struct Svf {
+    ic1eq: f64,
+    ic2eq: f64,
+}
+
+impl Svf {
+    // The g coefficient for a cutoff in Hz, k is 1/Q
+    pub fn coefficient(cutoff: f64, sample_rate: f64) -> f64 {
+        (PI * cutoff.clamp(10.0, sample_rate * 0.45) / sample_rate).tan()
+    }
+
+    // Returns (lowpass, highpass, bandpass, notch)
+    pub fn tick(&mut self, x: f64, g: f64, k: f64) -> (f64, f64, f64, f64) {
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        let v3 = x - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let low = v2;
+        let band = v1;
+        let high = x - k * v1 - v2;
+        (low, high, band, low + high)
+    }
+}
+
+// Four saturating one pole stages with global feedback like the Moog transistor ladder
+#[derive(Clone, Default)]
+struct Ladder {
+    stages: [f64; 4],
+}
+
+impl Ladder {
+    fn tick(&mut self, x: f64, g: f64, resonance: f64, drive: f64) -> f64 {
+        let feedback = 4.0 * resonance;
+        let mut input = (drive * x - feedback * self.stages[3]).tanh();
+        for stage in self.stages.iter_mut() {
+            *stage += g * (input - *stage);
+            input = *stage;
+        }
+        // Make up for the passband loss that comes with resonance, and for the drive
+        self.stages[3] * (1.0 + feedback * 0.5) / drive.sqrt()
+    }
+}
+
+// Multimode filter with a crossfade when the mode changes, so switching doesn't click.
+// - Input 0: audio.
+// - Input 1: cutoff frequency in Hz.
+// - Input 2: frequency of the note that is playing, used for keyboard tracking.
+// - Output 0: filtered audio.
+#[derive(Clone)]
+pub struct MultiFilter {
+    params: FilterParams,
+    svf: Svf,
+    ladder: Ladder,
+    mode: FilterMode,
+    previous: FilterMode,
+    // Goes from 0.0 to 1.0 after a mode change
+    fade: f64,
+    fade_step: f64,
+    sample_rate: f64,
+}
+
+impl MultiFilter {
+    const FADE_TIME: f64 = 0.01;
+
+    pub fn new(params: &FilterParams) -> Self {
+        let mut filter = Self {
+            params: params.clone(),
+            svf: Svf::default(),
+            ladder: Ladder::default(),
+            mode: FilterMode::from_value(params.mode.value()),
+            previous: FilterMode::default(),
+            fade: 1.0,
+            fade_step: 0.0,
+            sample_rate: DEFAULT_SR,
+        };
+        filter.set_sample_rate(DEFAULT_SR);
+        filter
+    }
+}
+
+impl AudioNode for MultiFilter {
+    const ID: u64 = 0x4f58_0002;
+    type Sample = f64;
+    type Inputs = U3;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.svf = Svf::default();
+        self.ladder = Ladder::default();
+        self.fade = 1.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.fade_step = 1.0 / (Self::FADE_TIME * sample_rate);
+    }
+
+    fn tick(&mut self, input: &Frame<f64, U3>) -> Frame<f64, U1> {
+        let mode = FilterMode::from_value(self.params.mode.value());
+        if mode != self.mode {
+            self.previous = self.mode;
+            self.mode = mode;
+            self.fade = 0.0;
+        }
+
+        let keytrack = self.params.keytrack.value();
+        let tracking = pow(max(input[2], 1.0) / midi_hz(60.0), keytrack);
+        let cutoff = clamp(10.0, self.sample_rate * 0.45, input[1] * tracking);
+        let resonance = self.params.resonance.value().clamp(0.0, 1.0);
+
+        // Both filters always run, so their state is ready when we fade over to them
+        let g = Svf::coefficient(cutoff, self.sample_rate);
+        let k = 2.0 - 1.96 * resonance;
+        let (low, high, band, notch) = self.svf.tick(input[0], g, k);
+        let ladder_g = 1.0 - (-2.0 * PI * cutoff / self.sample_rate).exp();
+        let ladder = self.ladder.tick(input[0], ladder_g, resonance, self.params.drive.value());
+
+        let output = |mode: FilterMode| match mode {
+            FilterMode::Lowpass => low,
+            FilterMode::Highpass => high,
+            FilterMode::Bandpass => band,
+            FilterMode::Notch => notch,
+            FilterMode::Ladder => ladder,
+        };
+
+        if self.fade < 1.0 {
+            self.fade = (self.fade + self.fade_step).min(1.0);
+            [lerp(output(self.previous), output(self.mode), self.fade)].into()
+        } else {
+            [output(self.mode)].into()
+        }
+    }
+}
+
+pub fn multi_filter(params: &FilterParams) -> An<MultiFilter> {
+    An(MultiFilter::new(params))
+}
@@ -0,0 +1,164 @@
+use std::f64::consts::{PI, TAU};
+use fundsp::hacker::*;
+
+use super::envelope::{Adsr, AdsrParams};
+use super::params::{Param, ParamRegistry};
+
+pub const OPERATORS: usize = 4;
+// Phase change in radians of an operator at full feedback
+const FEEDBACK_DEPTH: f64 = PI / 2.0;
+
+// How the operators are connected. Operators only modulate operators with a lower number,
+// the carriers are the ones that are heard.
+pub struct Algorithm {
+    pub name: &'static str,
+    modulators: [&'static [usize]; OPERATORS],
+    carriers: &'static [usize],
+}
+
+// The eight algorithms of the classic four operator synths, operators counted from 0 here
+pub const ALGORITHMS: [Algorithm; 8] = [
+    Algorithm { name: "4>3>2>1", modulators: [&[1], &[2], &[3], &[]], carriers: &[0] },
+    Algorithm { name: "(3+4)>2>1", modulators: [&[1], &[2, 3], &[], &[]], carriers: &[0] },
+    Algorithm { name: "(3>2)+4>1", modulators: [&[1, 3], &[2], &[], &[]], carriers: &[0] },
+    Algorithm { name: "(4>3)+2>1", modulators: [&[1, 2], &[], &[3], &[]], carriers: &[0] },
+    Algorithm { name: "2>1 + 4>3", modulators: [&[1], &[], &[3], &[]], carriers: &[0, 2] },
+    Algorithm { name: "4>(1+2+3)", modulators: [&[3], &[3], &[3], &[]], carriers: &[0, 1, 2] },
+    Algorithm { name: "1 + 2 + 4>3", modulators: [&[], &[], &[3], &[]], carriers: &[0, 1, 2] },
+    Algorithm { name: "1 + 2 + 3 + 4", modulators: [&[], &[], &[], &[]], carriers: &[0, 1, 2, 3] },
+];
+
+#[derive(Clone)]
+pub struct OperatorParams {
+    // Frequency as a multiple of the note, or in Hz when fixed is on
+    pub ratio: Param,
+    pub fixed: Param,
+    pub frequency: Param,
+    // Output of a carrier, or modulation depth of a modulator
+    pub level: Param,
+    pub feedback: Param,
+    pub env: AdsrParams,
+}
+
+// The parameters of the whole FM engine, "fm.algorithm", "fm.op1.ratio", "fm.op1.env.attack" and so on
+#[derive(Clone)]
+pub struct FmParams {
+    pub algorithm: Param,
+    // Scales all modulation, in radians of phase change at full level
+    pub index: Param,
+    pub operators: Vec<OperatorParams>,
+}
+
+impl FmParams {
+    // Starts out as the simple two operator patch: operator 2 at the note frequency modulating operator 1
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        let operators = (1..=OPERATORS).map(|n| {
+            let prefix = format!("{prefix}.op{n}");
+            OperatorParams {
+                ratio: registry.add(&format!("{prefix}.ratio"), 0.125, 16.0, 1.0),
+                fixed: registry.add(&format!("{prefix}.fixed"), 0.0, 1.0, 0.0),
+                frequency: registry.add(&format!("{prefix}.frequency"), 1.0, 8000.0, 440.0),
+                level: registry.add(&format!("{prefix}.level"), 0.0, 1.0, if n <= 2 { 1.0 } else { 0.0 }),
+                feedback: registry.add(&format!("{prefix}.feedback"), 0.0, 1.0, 0.0),
+                env: AdsrParams::new(registry, &format!("{prefix}.env"), 0.002, 0.2, 1.0, 0.1),
+            }
+        }).collect();
+        Self {
+            algorithm: registry.add(&format!("{prefix}.algorithm"), 0.0, (ALGORITHMS.len() - 1) as f64, 0.0),
+            index: registry.add(&format!("{prefix}.index"), 0.0, 10.0, 5.0),
+            operators,
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static Algorithm {
+        &ALGORITHMS[self.algorithm.value().round().clamp(0.0, (ALGORITHMS.len() - 1) as f64) as usize]
+    }
+}
+
+#[derive(Clone, Default)]
+struct Operator {
+    phase: f64,
+    env: Adsr,
+    // The last two outputs, their average is fed back so the feedback doesn't turn into noise
+    history: [f64; 2],
+    output: f64,
+}
+
+// One voice of the FM engine. Every operator is a sine with its own envelope, phase modulated
+// by the operators the algorithm connects to it.
+// - Input 0: frequency of the note in Hz.
+// - Input 1: gate. Zero closes the gate, any new positive value (re)triggers the envelopes.
+// - Input 2: velocity.
+// - Output 0: the carriers mixed together.
+#[derive(Clone)]
+pub struct FmVoice {
+    params: FmParams,
+    operators: [Operator; OPERATORS],
+    last_gate: f64,
+    sample_duration: f64,
+}
+
+impl FmVoice {
+    pub fn new(params: &FmParams) -> Self {
+        Self {
+            params: params.clone(),
+            operators: Default::default(),
+            last_gate: 0.0,
+            sample_duration: 1.0 / DEFAULT_SR,
+        }
+    }
+}
+
+impl AudioNode for FmVoice {
+    const ID: u64 = 0x4f58_0007;
+    type Sample = f64;
+    type Inputs = U3;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.operators = Default::default();
+        self.last_gate = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_duration = 1.0 / sample_rate;
+    }
+
+    fn tick(&mut self, input: &Frame<f64, U3>) -> Frame<f64, U1> {
+        let (freq, gate, velocity) = (input[0], input[1], input[2]);
+        let retrigger = gate > 0.0 && gate != self.last_gate;
+        self.last_gate = gate;
+        let algorithm = self.params.algorithm();
+        let index = self.params.index.value();
+
+        // From the last operator to the first, so the modulators are done before the ones they modulate
+        for i in (0..OPERATORS).rev() {
+            let params = &self.params.operators[i];
+            let modulation: f64 = algorithm.modulators[i].iter().map(|j| self.operators[*j].output).sum();
+            let operator = &mut self.operators[i];
+            if retrigger {
+                operator.env.retrigger();
+            } else if gate <= 0.0 {
+                operator.env.set_gate(false);
+            }
+            params.env.update(&mut operator.env);
+            let level = operator.env.next(self.sample_duration) * params.env.velocity_scale(velocity) * params.level.value();
+
+            let feedback = (operator.history[0] + operator.history[1]) * 0.5 * params.feedback.value() * FEEDBACK_DEPTH;
+            let x = (TAU * operator.phase + modulation * index + feedback).sin();
+            operator.history = [operator.history[1], x];
+            operator.output = x * level;
+
+            let operator_freq = if params.fixed.value() >= 0.5 { params.frequency.value() } else { freq * params.ratio.value() };
+            operator.phase = (operator.phase + operator_freq * self.sample_duration).fract();
+        }
+        let carriers = algorithm.carriers;
+        let output: f64 = carriers.iter().map(|i| self.operators[*i].output).sum();
+        [output / carriers.len() as f64].into()
+    }
+}
+
+pub fn fm_voice(params: &FmParams) -> An<FmVoice> {
+    An(FmVoice::new(params))
+}
@@ -0,0 +1,318 @@
+use std::f64::consts::{FRAC_PI_2, TAU};
+use std::sync::{Arc, Mutex};
+use fundsp::hacker::*;
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use super::noise::Noise;
+use super::params::{Param, ParamRegistry};
+use super::sampler::Sample;
+
+// Most grains sounding at once, new ones are skipped when they're all busy
+const MAX_GRAINS: usize = 64;
+// Seconds of live audio the engine keeps to take grains from
+const LIVE_SECONDS: f64 = 4.0;
+// Live audio is moved in and grain activity is published once per this many frames
+const BLOCK: usize = 64;
+// Frames of live audio that can wait to be moved in, the ring can jump ahead by that much at once
+const QUEUE: usize = BLOCK * 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GrainWindow {
+    #[default]
+    Hann,
+    Triangle,
+    Gaussian,
+    // Quick fade in and out with a flat top, sounds the most like the source
+    Trapezoid,
+}
+
+impl GrainWindow {
+    pub const ALL: [GrainWindow; 4] = [GrainWindow::Hann, GrainWindow::Triangle, GrainWindow::Gaussian, GrainWindow::Trapezoid];
+
+    pub fn from_value(value: f64) -> Self {
+        let index = value.round().clamp(0.0, (Self::ALL.len() - 1) as f64);
+        Self::ALL[index as usize]
+    }
+
+    // Gain at a point of the grain from 0.0 to 1.0
+    fn gain(&self, x: f64) -> f64 {
+        match self {
+            GrainWindow::Hann => 0.5 - 0.5 * (TAU * x).cos(),
+            GrainWindow::Triangle => 1.0 - (2.0 * x - 1.0).abs(),
+            GrainWindow::Gaussian => (-0.5 * ((x - 0.5) / 0.15).powi(2)).exp(),
+            GrainWindow::Trapezoid => (4.0 * x.min(1.0 - x)).min(1.0),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GranularParams {
+    // Grain length in seconds
+    pub size: Param,
+    // New grains per second
+    pub density: Param,
+    // Where in the source the grains start, 0.0 is the start of the sample or the oldest live audio
+    pub position: Param,
+    // Random offset of the start, as a part of the source
+    pub jitter: Param,
+    // Playback speed of the grains in semitones
+    pub pitch: Param,
+    // How far the grains are randomly panned from the middle
+    pub spread: Param,
+    pub window: Param,
+    // 0.0 takes grains from the sample, 1.0 from the live buffer
+    pub source: Param,
+    // Stops recording into the live buffer, so the grains keep playing what is in there
+    pub freeze: Param,
+}
+
+impl GranularParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            size: registry.add(&format!("{prefix}.size"), 0.005, 0.5, 0.08),
+            density: registry.add(&format!("{prefix}.density"), 1.0, 200.0, 20.0),
+            position: registry.add(&format!("{prefix}.position"), 0.0, 1.0, 0.5),
+            jitter: registry.add(&format!("{prefix}.jitter"), 0.0, 1.0, 0.05),
+            pitch: registry.add(&format!("{prefix}.pitch"), -24.0, 24.0, 0.0),
+            spread: registry.add(&format!("{prefix}.spread"), 0.0, 1.0, 0.5),
+            window: registry.add(&format!("{prefix}.window"), 0.0, (GrainWindow::ALL.len() - 1) as f64, 0.0),
+            source: registry.add(&format!("{prefix}.source"), 0.0, 1.0, 0.0),
+            freeze: registry.add(&format!("{prefix}.freeze"), 0.0, 1.0, 0.0),
+        }
+    }
+}
+
+// What the visuals get to see of a grain
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GrainState {
+    // Read position as a part of the source, 0.0 to 1.0
+    pub position: f64,
+    // Current gain of the window
+    pub amplitude: f64,
+    // -1.0 is left, 1.0 is right
+    pub pan: f64,
+}
+
+// The UI end of the grain activity, updated by the audio thread once per block
+#[derive(Clone, Default)]
+pub struct GrainActivity(Arc<Mutex<Vec<GrainState>>>);
+
+impl GrainActivity {
+    pub fn grains(&self) -> Vec<GrainState> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Grain {
+    active: bool,
+    // Read position and step in frames of the source
+    position: f64,
+    step: f64,
+    // How far the grain is, 0.0 to 1.0, and how much that moves per frame
+    progress: f64,
+    increment: f64,
+    window: GrainWindow,
+    pan: f64,
+    gains: (f64, f64),
+    amplitude: f64,
+    live: bool,
+}
+
+// Grains from a sample or from a live buffer that the audio input writes to.
+// - Output 0: left.
+// - Output 1: right.
+#[derive(Clone)]
+pub struct Granular {
+    params: GranularParams,
+    sample: Option<Arc<Sample>>,
+    live_input: Arc<Mutex<Consumer<[f32; 2]>>>,
+    // Ring of the most recent live audio, write is where the oldest frame is
+    live: Vec<[f32; 2]>,
+    write: usize,
+    grains: [Grain; MAX_GRAINS],
+    // Counts up to the next grain
+    schedule: f64,
+    noise: Noise,
+    activity: GrainActivity,
+    frame: usize,
+    sample_rate: f64,
+}
+
+impl Granular {
+    // Returns the node and the end of the ring buffer for the live audio, see AudioInput::add_live_output
+    pub fn new(registry: &ParamRegistry, prefix: &str, sample: Option<Arc<Sample>>) -> (Self, Producer<[f32; 2]>) {
+        let (producer, consumer) = RingBuffer::new(QUEUE);
+        let mut granular = Self {
+            params: GranularParams::new(registry, prefix),
+            sample,
+            live_input: Arc::new(Mutex::new(consumer)),
+            live: Vec::new(),
+            write: 0,
+            grains: [Grain::default(); MAX_GRAINS],
+            schedule: 0.0,
+            noise: Noise::new(0x2545_f491),
+            activity: GrainActivity(Arc::new(Mutex::new(Vec::with_capacity(MAX_GRAINS)))),
+            frame: 0,
+            sample_rate: DEFAULT_SR,
+        };
+        // Without a sample the live buffer is the only thing there is
+        if granular.sample.is_none() {
+            granular.params.source.set(1.0);
+        }
+        granular.allocate();
+        (granular, producer)
+    }
+
+    pub fn params(&self) -> &GranularParams {
+        &self.params
+    }
+
+    pub fn activity(&self) -> GrainActivity {
+        self.activity.clone()
+    }
+
+    fn allocate(&mut self) {
+        self.live = vec![[0.0; 2]; (LIVE_SECONDS * self.sample_rate) as usize];
+        self.write = 0;
+    }
+
+    fn use_live(&self) -> bool {
+        self.sample.is_none() || self.params.source.value() >= 0.5
+    }
+
+    fn source_len(&self, live: bool) -> usize {
+        match &self.sample {
+            Some(sample) if !live => sample.len(),
+            _ => self.live.len(),
+        }
+    }
+
+    fn read(&self, grain: &Grain) -> (f64, f64) {
+        match &self.sample {
+            Some(sample) if !grain.live => sample.read(grain.position),
+            _ => {
+                // Linear is enough here, the live buffer runs at our own rate
+                let len = self.live.len();
+                let index = grain.position.floor();
+                let t = grain.position - index;
+                let a = self.live[index as usize % len];
+                let b = self.live[(index as usize + 1) % len];
+                (lerp(a[0] as f64, b[0] as f64, t), lerp(a[1] as f64, b[1] as f64, t))
+            },
+        }
+    }
+
+    fn spawn(&mut self) {
+        let Some(index) = self.grains.iter().position(|grain| !grain.active) else { return };
+        let live = self.use_live();
+        let len = self.source_len(live) as f64;
+        let jitter = (self.noise.unipolar() * 2.0 - 1.0) * self.params.jitter.value();
+        let pan = (self.noise.unipolar() * 2.0 - 1.0) * self.params.spread.value();
+        let rate = match &self.sample {
+            Some(sample) if !live => sample.sample_rate / self.sample_rate,
+            _ => 1.0,
+        };
+        let step = rate * exp2(self.params.pitch.value() / 12.0);
+        let length = (self.params.size.value() * self.sample_rate).max(1.0);
+        // Keep the whole grain inside the source
+        let last = (len - 2.0 - length * step).max(0.0);
+        // The ring overwrites its oldest frames while a live grain plays, so it starts far enough
+        // from them that they can't catch up with it, even when it plays slower than they move
+        let first = if live { (length * step.max(1.0) + (QUEUE + BLOCK) as f64).min(last) } else { 0.0 };
+        let mut position = ((self.params.position.value() + jitter) * len).clamp(first, last);
+        // Live grains read the ring at fixed places, counted from the oldest frame when they start
+        if live {
+            position += self.write as f64;
+        }
+        let angle = (pan + 1.0) * 0.5 * FRAC_PI_2;
+        self.grains[index] = Grain {
+            active: true,
+            position,
+            step,
+            progress: 0.0,
+            increment: 1.0 / length,
+            window: GrainWindow::from_value(self.params.window.value()),
+            pan,
+            gains: (angle.cos(), angle.sin()),
+            amplitude: 0.0,
+            live,
+        };
+    }
+
+    // Moves the live audio that arrived into the ring, and tells the visuals what the grains are doing
+    fn update_block(&mut self) {
+        let freeze = self.params.freeze.value() >= 0.5;
+        if let Ok(mut input) = self.live_input.try_lock() {
+            let len = self.live.len();
+            while let Ok(frame) = input.pop() {
+                if !freeze {
+                    self.live[self.write] = frame;
+                    self.write = (self.write + 1) % len;
+                }
+            }
+        }
+        if let Ok(mut activity) = self.activity.0.try_lock() {
+            activity.clear();
+            for grain in self.grains.iter().filter(|grain| grain.active) {
+                let len = self.source_len(grain.live) as f64;
+                let position = if grain.live { (grain.position - self.write as f64).rem_euclid(len) } else { grain.position };
+                activity.push(GrainState { position: position / len, amplitude: grain.amplitude, pan: grain.pan });
+            }
+        }
+    }
+}
+
+impl AudioNode for Granular {
+    const ID: u64 = 0x4f58_0008;
+    type Sample = f64;
+    type Inputs = U0;
+    type Outputs = U2;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.grains = [Grain::default(); MAX_GRAINS];
+        self.schedule = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.allocate();
+        }
+    }
+
+    fn tick(&mut self, _input: &Frame<f64, U0>) -> Frame<f64, U2> {
+        if self.frame.is_multiple_of(BLOCK) {
+            self.update_block();
+        }
+        self.frame += 1;
+
+        self.schedule += self.params.density.value() / self.sample_rate;
+        if self.schedule >= 1.0 {
+            self.schedule -= 1.0;
+            self.spawn();
+        }
+
+        let (mut left, mut right) = (0.0, 0.0);
+        for i in 0..MAX_GRAINS {
+            let grain = self.grains[i];
+            if !grain.active {
+                continue;
+            }
+            let (l, r) = self.read(&grain);
+            let gain = grain.window.gain(grain.progress);
+            left += l * gain * grain.gains.0;
+            right += r * gain * grain.gains.1;
+            let grain = &mut self.grains[i];
+            grain.amplitude = gain;
+            grain.position += grain.step;
+            grain.progress += grain.increment;
+            grain.active = grain.progress < 1.0;
+        }
+        // Many grains on top of each other add up, keep the level about the same
+        let overlap = (self.params.density.value() * self.params.size.value()).max(1.0);
+        let gain = 1.0 / overlap.sqrt();
+        [left * gain, right * gain].into()
+    }
+}
@@ -0,0 +1,248 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use fundsp::hacker::*;
+use rtrb::{Consumer, Producer, PushError, RingBuffer};
+
+// Length of the crossfade from the old graph to the new one, in seconds
+const FADE_TIME: f64 = 0.05;
+// How often the builder thread frees graphs the audio thread is done with
+const COLLECT_INTERVAL: Duration = Duration::from_millis(50);
+
+type Build = Box<dyn FnOnce() -> Box<dyn AudioUnit64> + Send>;
+// A new graph and the sample rate it was prepared for
+type Prepared = (Box<dyn AudioUnit64>, f64);
+
+// What the audio thread sends back to the builder thread
+enum Returned {
+    // Faded out, to be freed
+    Retired(Box<dyn AudioUnit64>),
+    // Prepared for a sample rate that changed before it was swapped in, to be prepared again
+    Stale(Box<dyn AudioUnit64>),
+}
+
+// A place in the audio graph whose contents can be replaced while the stream runs.
+// Replacements are built and prepared on a thread of their own, the audio thread picks them up
+// and crossfades from the old graph to the new one. The old graph goes back to that thread to be freed,
+// and so does a new one that was prepared for another sample rate, to be prepared again.
+// Replacements need the same number of inputs and outputs as the first graph.
+// - Input(s): the inputs of the graph
+// - Output(s): the outputs of the graph
+pub struct HotSwap {
+    current: Box<dyn AudioUnit64>,
+    // The graph that is fading in
+    next: Option<Box<dyn AudioUnit64>>,
+    // A graph that couldn't be sent back yet
+    returned: Option<Returned>,
+    // How far the crossfade is, from 0.0 to 1.0
+    phase: f64,
+    // In a Mutex only to be Sync, the audio thread has &mut self and never waits for it
+    incoming: Mutex<Consumer<Prepared>>,
+    outgoing: Mutex<Producer<Returned>>,
+    // Output of the next graph during a crossfade, and frames for process()
+    next_output: Vec<f64>,
+    input_frame: Vec<f64>,
+    output_frame: Vec<f64>,
+    // Shared with the builder thread, which prepares new graphs for this rate
+    sample_rate: Shared<f64>,
+}
+
+// Sends replacement graphs to a HotSwap
+#[derive(Clone)]
+pub struct GraphSwapper {
+    requests: mpsc::Sender<Build>,
+}
+
+impl GraphSwapper {
+    // Builds a graph on the builder thread and swaps it in. When several arrive faster than
+    // they can be crossfaded the latest one wins.
+    pub fn swap(&self, build: impl FnOnce() -> Box<dyn AudioUnit64> + Send + 'static) {
+        // Only fails when the builder thread is gone, then there's nothing to swap anyway
+        let _ = self.requests.send(Box::new(build));
+    }
+}
+
+// Starts the builder thread, which stops when the GraphSwapper is dropped
+pub fn hot_swap(initial: Box<dyn AudioUnit64>) -> (HotSwap, GraphSwapper) {
+    let (inputs, outputs) = (initial.inputs(), initial.outputs());
+    // One waiting graph is enough, newer ones replace it on the builder thread
+    let (incoming_producer, incoming) = RingBuffer::new(1);
+    let (outgoing, outgoing_consumer) = RingBuffer::new(4);
+    let (requests, receiver) = mpsc::channel();
+    let sample_rate = shared(DEFAULT_SR);
+    let builder_rate = sample_rate.clone();
+    thread::spawn(move || build_graphs(receiver, incoming_producer, outgoing_consumer, builder_rate, inputs, outputs));
+    let swap = HotSwap {
+        current: initial,
+        next: None,
+        returned: None,
+        phase: 0.0,
+        incoming: Mutex::new(incoming),
+        outgoing: Mutex::new(outgoing),
+        next_output: vec![0.0; outputs],
+        input_frame: vec![0.0; inputs],
+        output_frame: vec![0.0; outputs],
+        sample_rate,
+    };
+    (swap, GraphSwapper { requests })
+}
+
+fn build_graphs(
+    requests: mpsc::Receiver<Build>,
+    mut incoming: Producer<Prepared>,
+    mut outgoing: Consumer<Returned>,
+    sample_rate: Shared<f64>,
+    inputs: usize,
+    outputs: usize,
+) {
+    let mut waiting: Option<Prepared> = None;
+    loop {
+        match requests.recv_timeout(COLLECT_INTERVAL) {
+            Ok(build) => {
+                let unit = build();
+                if unit.inputs() != inputs || unit.outputs() != outputs {
+                    eprintln!("The new graph has {} inputs and {} outputs instead of {inputs} and {outputs}, it is not swapped in.", unit.inputs(), unit.outputs());
+                    continue;
+                }
+                waiting = Some(prepare(unit, &sample_rate));
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        while let Ok(returned) = outgoing.pop() {
+            match returned {
+                // Dropping them here is what frees the old graphs
+                Returned::Retired(unit) => drop(unit),
+                // Unless a newer graph is already waiting, that one wins
+                Returned::Stale(unit) => if waiting.is_none() {
+                    waiting = Some(prepare(unit, &sample_rate));
+                },
+            }
+        }
+        if let Some(unit) = waiting.take() && let Err(PushError::Full(unit)) = incoming.push(unit) {
+            waiting = Some(unit);
+        }
+    }
+}
+
+// Everything that allocates happens here instead of on the audio thread
+fn prepare(mut unit: Box<dyn AudioUnit64>, sample_rate: &Shared<f64>) -> Prepared {
+    let rate = sample_rate.value();
+    unit.set_sample_rate(rate);
+    unit.allocate();
+    (unit, rate)
+}
+
+impl HotSwap {
+    fn receive(&mut self) {
+        let (Ok(incoming), Ok(outgoing)) = (self.incoming.get_mut(), self.outgoing.get_mut()) else { return };
+        if let Some(unit) = self.returned.take() && let Err(PushError::Full(unit)) = outgoing.push(unit) {
+            self.returned = Some(unit);
+        }
+        // The next crossfade waits until the old graph of the last one is on its way out
+        if self.next.is_none() && self.returned.is_none() && let Ok((unit, rate)) = incoming.pop() {
+            // The rate changed after the builder prepared it, that only happens when the stream is set up again
+            if rate != self.sample_rate.value() {
+                self.returned = Some(Returned::Stale(unit));
+            } else {
+                self.next = Some(unit);
+                self.phase = 0.0;
+            }
+        }
+    }
+}
+
+// A clone plays the same graph, but nothing can be swapped into it
+impl Clone for HotSwap {
+    fn clone(&self) -> Self {
+        let (_, incoming) = RingBuffer::new(1);
+        let (outgoing, _) = RingBuffer::new(1);
+        Self {
+            current: self.current.clone(),
+            next: None,
+            returned: None,
+            phase: 0.0,
+            incoming: Mutex::new(incoming),
+            outgoing: Mutex::new(outgoing),
+            next_output: self.next_output.clone(),
+            input_frame: self.input_frame.clone(),
+            output_frame: self.output_frame.clone(),
+            sample_rate: shared(self.sample_rate.value()),
+        }
+    }
+}
+
+impl AudioUnit64 for HotSwap {
+    fn reset(&mut self) {
+        self.current.reset();
+        if let Some(next) = self.next.as_mut() {
+            next.reset();
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate.set_value(sample_rate);
+        self.current.set_sample_rate(sample_rate);
+        if let Some(next) = self.next.as_mut() {
+            next.set_sample_rate(sample_rate);
+        }
+    }
+
+    fn tick(&mut self, input: &[f64], output: &mut [f64]) {
+        self.receive();
+        self.current.tick(input, output);
+        let Some(next) = self.next.as_mut() else { return };
+        next.tick(input, &mut self.next_output);
+        // Equal power, the two graphs have nothing to do with each other
+        for (x, y) in output.iter_mut().zip(self.next_output.iter()) {
+            *x = *x * Fade::Power.at(1.0 - self.phase) + *y * Fade::Power.at(self.phase);
+        }
+        self.phase += 1.0 / (FADE_TIME * self.sample_rate.value());
+        if self.phase >= 1.0 {
+            let mut old = self.next.take().unwrap();
+            std::mem::swap(&mut self.current, &mut old);
+            self.returned = Some(Returned::Retired(old));
+        }
+    }
+
+    fn process(&mut self, size: usize, input: &[&[f64]], output: &mut [&mut [f64]]) {
+        let mut input_frame = std::mem::take(&mut self.input_frame);
+        let mut output_frame = std::mem::take(&mut self.output_frame);
+        for i in 0..size {
+            for (x, channel) in input_frame.iter_mut().zip(input) {
+                *x = channel[i];
+            }
+            self.tick(&input_frame, &mut output_frame);
+            for (channel, y) in output.iter_mut().zip(&output_frame) {
+                channel[i] = *y;
+            }
+        }
+        self.input_frame = input_frame;
+        self.output_frame = output_frame;
+    }
+
+    fn inputs(&self) -> usize {
+        self.input_frame.len()
+    }
+
+    fn outputs(&self) -> usize {
+        self.output_frame.len()
+    }
+
+    fn route(&mut self, input: &SignalFrame, frequency: f64) -> SignalFrame {
+        self.current.route(input, frequency)
+    }
+
+    fn get_id(&self) -> u64 {
+        0x4f58_000c
+    }
+
+    fn footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn allocate(&mut self) {
+        self.current.allocate();
+    }
+}
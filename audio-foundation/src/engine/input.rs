@@ -0,0 +1,189 @@
+use std::{error::Error, sync::{Arc, Mutex}};
+use cpal::traits::{DeviceTrait, HostTrait};
+use fundsp::hacker::*;
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use super::analysis::AnalysisTap;
+use super::params::{Param, ParamRegistry};
+use super::sampler::Sample;
+
+// Frames are moved out of the ring buffer in blocks of this size, so the lock is taken once per block
+const BLOCK: usize = 64;
+// How many frames we let wait in the ring buffer before we start dropping the oldest
+const LATENCY: usize = BLOCK * 2;
+
+// Where the input comes from: the input device, or a WAV file that loops forever so the
+// input path can be tried without a microphone or sound card
+#[derive(Clone)]
+enum Source {
+    Device(Arc<Mutex<Consumer<[f32; 2]>>>),
+    File(Arc<Sample>),
+}
+
+// External audio as a source in the graph.
+// - Output 0: left.
+// - Output 1: right.
+#[derive(Clone)]
+pub struct AudioInput {
+    source: Source,
+    gain: Param,
+    block: [[f32; 2]; BLOCK],
+    // Read position in the block, or in the file
+    index: usize,
+    position: f64,
+    // Peak follower for the visuals
+    level: Shared<f64>,
+    envelope: f64,
+    // Sends the input to an analyzer and to live buffers (of the granular engine and such) one block at a time
+    tap: Option<Arc<Mutex<AnalysisTap>>>,
+    live_outputs: Vec<Arc<Mutex<Producer<[f32; 2]>>>>,
+    recent: [[f32; 2]; BLOCK],
+    recent_count: usize,
+    sample_rate: f64,
+}
+
+impl AudioInput {
+    // Returns the node and the end of the ring buffer that the input stream writes to
+    pub fn device(registry: &ParamRegistry, prefix: &str, sample_rate: f64) -> (Self, Producer<[f32; 2]>) {
+        // Room for a quarter of a second, but we only keep LATENCY frames in there
+        let (producer, consumer) = RingBuffer::new((sample_rate * 0.25) as usize);
+        (Self::new(registry, prefix, Source::Device(Arc::new(Mutex::new(consumer)))), producer)
+    }
+
+    pub fn file(registry: &ParamRegistry, prefix: &str, sample: Sample) -> Self {
+        Self::new(registry, prefix, Source::File(Arc::new(sample)))
+    }
+
+    fn new(registry: &ParamRegistry, prefix: &str, source: Source) -> Self {
+        Self {
+            source,
+            gain: registry.add(&format!("{prefix}.gain"), 0.0, 4.0, 1.0),
+            block: [[0.0; 2]; BLOCK],
+            index: BLOCK,
+            position: 0.0,
+            level: shared(0.0),
+            envelope: 0.0,
+            tap: None,
+            live_outputs: Vec::new(),
+            recent: [[0.0; 2]; BLOCK],
+            recent_count: 0,
+            sample_rate: DEFAULT_SR,
+        }
+    }
+
+    // Input level from 0.0 up, after the gain
+    pub fn level(&self) -> Shared<f64> {
+        self.level.clone()
+    }
+
+    pub fn set_tap(&mut self, tap: AnalysisTap) {
+        self.tap = Some(Arc::new(Mutex::new(tap)));
+    }
+
+    // Also copies the input into this ring buffer, frames that don't fit are dropped
+    pub fn add_live_output(&mut self, producer: Producer<[f32; 2]>) {
+        self.live_outputs.push(Arc::new(Mutex::new(producer)));
+    }
+
+    fn next_frame(&mut self) -> [f32; 2] {
+        match &self.source {
+            Source::File(sample) => {
+                let (left, right) = sample.read(self.position);
+                self.position = (self.position + sample.sample_rate / self.sample_rate) % sample.len() as f64;
+                [left as f32, right as f32]
+            },
+            Source::Device(consumer) => {
+                if self.index == BLOCK {
+                    self.index = 0;
+                    // Frames the input stream didn't deliver in time are silence
+                    self.block = [[0.0; 2]; BLOCK];
+                    if let Ok(mut consumer) = consumer.try_lock() {
+                        // Drop what piled up while we were not reading, so the delay stays short
+                        while consumer.slots() > LATENCY + BLOCK {
+                            let _ = consumer.pop();
+                        }
+                        for frame in self.block.iter_mut() {
+                            match consumer.pop() {
+                                Ok(input) => *frame = input,
+                                Err(_) => break,
+                            }
+                        }
+                    }
+                }
+                self.index += 1;
+                self.block[self.index - 1]
+            },
+        }
+    }
+}
+
+impl AudioNode for AudioInput {
+    const ID: u64 = 0x4f58_0005;
+    type Sample = f64;
+    type Inputs = U0;
+    type Outputs = U2;
+    type Setting = ();
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, _input: &Frame<f64, U0>) -> Frame<f64, U2> {
+        let [left, right] = self.next_frame();
+        let gain = self.gain.value();
+        let (left, right) = (left as f64 * gain, right as f64 * gain);
+
+        // Fast attack, 300 ms release
+        let peak = left.abs().max(right.abs());
+        self.envelope = if peak > self.envelope { peak } else { self.envelope * (-1.0 / (0.3 * self.sample_rate)).exp() };
+        self.level.set_value(self.envelope);
+
+        if self.tap.is_some() || !self.live_outputs.is_empty() {
+            self.recent[self.recent_count] = [left as f32, right as f32];
+            self.recent_count += 1;
+            if self.recent_count == BLOCK {
+                self.recent_count = 0;
+                if let Some(tap) = &self.tap && let Ok(mut tap) = tap.try_lock() {
+                    for [left, right] in self.recent {
+                        tap.push(left as f64, right as f64);
+                    }
+                }
+                for output in &self.live_outputs {
+                    let Ok(mut output) = output.try_lock() else { continue };
+                    for frame in self.recent {
+                        if output.push(frame).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        [left, right].into()
+    }
+}
+
+// Opens the default input device at our sample rate and pushes its frames into the ring buffer.
+// Mono inputs are copied to both sides, channels above two are ignored.
+pub fn start_input_stream(sample_rate: f64, mut producer: Producer<[f32; 2]>) -> Result<cpal::Stream, Box<dyn Error>> {
+    let host = cpal::default_host();
+    let device = host.default_input_device().ok_or("No input device available.")?;
+    println!("Input device: {:?}", device.name());
+
+    // There is no resampling on the input, so the device has to run at the output rate
+    let config = device.supported_input_configs()?
+        .filter(|config| config.sample_format() == cpal::SampleFormat::F32)
+        .find(|config| (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&(sample_rate as u32)))
+        .ok_or("The input device can't record f32 at the output sample rate.")?
+        .with_sample_rate(cpal::SampleRate(sample_rate as u32));
+    let channels = config.channels() as usize;
+    let stream_config: cpal::StreamConfig = config.into();
+
+    let input_callback = move |data: &[f32], _: &cpal::InputCallbackInfo| {
+        for frame in data.chunks(channels) {
+            // When the ring is full the audio thread is behind, dropping input is all we can do
+            let _ = producer.push([frame[0], frame[if channels > 1 { 1 } else { 0 }]]);
+        }
+    };
+    let err_fn = |err| eprintln!("An error occurred on the input stream: {}", err);
+    Ok(device.build_input_stream(&stream_config, input_callback, err_fn, None)?)
+}
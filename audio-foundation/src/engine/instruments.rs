@@ -0,0 +1,190 @@
+use std::sync::{Arc, Mutex};
+use fundsp::hacker::*;
+use serde::{Deserialize, Serialize};
+
+use super::drums::{DrumMachine, DrumTriggers};
+use super::envelope::{live_adsr, AdsrParams};
+use super::faust::{tremolo::Tremolo, FaustNode};
+use super::filter::{multi_filter, FilterMode, FilterParams};
+use super::fm::{fm_voice, FmParams};
+use super::hotswap::{hot_swap, GraphSwapper};
+use super::mixer::Mixer;
+use super::modulation::{LfoShape, LfoSync, ModMatrix, ModSource};
+use super::oscillator::{unison_oscillator, OscillatorParams};
+use super::params::{Param, ParamRegistry};
+use super::tuning::Tuning;
+use super::voice::{Voice, VoiceAllocator};
+
+pub const VOICES: usize = 4;
+
+// What the lead voices play. Changing it builds the lead track again, which is swapped in while the stream runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LeadOscillator {
+    #[default]
+    Fm,
+    // The unison oscillator of the pad, with its own parameters under "lead.osc"
+    Unison,
+}
+
+impl LeadOscillator {
+    pub const ALL: [LeadOscillator; 2] = [LeadOscillator::Fm, LeadOscillator::Unison];
+}
+
+// The lead for a single voice, with its own amplitude and filter envelope
+fn lead_voice(voice: &Voice, oscillator: LeadOscillator, graph: &LeadGraph) -> Net64 {
+    let source = match oscillator {
+        LeadOscillator::Fm => Net64::wrap(Box::new(oversample((var(&voice.freq) | var(&voice.gate) | var(&voice.velocity)) >> fm_voice(&graph.fm)))),
+        LeadOscillator::Unison => Net64::wrap(Box::new(var(&voice.freq) >> unison_oscillator(&graph.osc) >> join::<U2>())),
+    };
+    // The filter envelope moves the cutoff up by filter_env.amount octaves
+    let cutoff = (var(graph.filter.cutoff.shared()) | ((var(&voice.gate) | var(&voice.velocity)) >> live_adsr(&graph.filter_env)) | var(graph.filter_env_amount.shared()))
+        >> map(|f: &Frame<f64, U3>| clamp(20.0, 20000.0, f[0] * exp2(f[1] * f[2])));
+    let filter = (pass() | cutoff | var(&voice.freq)) >> multi_filter(&graph.filter);
+    let env = (var(&voice.gate) | var(&voice.velocity)) >> live_adsr(&graph.amp_env);
+    source >> Net64::wrap(Box::new(filter * env))
+}
+
+// Everything the lead track is built from
+#[derive(Clone)]
+struct LeadGraph {
+    voices: Vec<Voice>,
+    fm: FmParams,
+    osc: OscillatorParams,
+    filter: FilterParams,
+    amp_env: AdsrParams,
+    filter_env: AdsrParams,
+    filter_env_amount: Param,
+}
+
+impl LeadGraph {
+    fn build(&self, oscillator: LeadOscillator) -> Net64 {
+        let synth = self.voices.iter()
+            .map(|voice| lead_voice(voice, oscillator, self))
+            .reduce(|a, b| a + b)
+            .unwrap();
+        synth * 0.2
+    }
+}
+
+// Switches the oscillator of the lead, from the UI thread or when a preset is loaded
+#[derive(Clone)]
+pub struct Lead {
+    graph: LeadGraph,
+    oscillator: Arc<Mutex<LeadOscillator>>,
+    swapper: GraphSwapper,
+}
+
+impl Lead {
+    pub fn oscillator(&self) -> LeadOscillator {
+        *self.oscillator.lock().unwrap()
+    }
+
+    pub fn set_oscillator(&self, oscillator: LeadOscillator) {
+        let mut current = self.oscillator.lock().unwrap();
+        if *current != oscillator {
+            *current = oscillator;
+            let graph = self.graph.clone();
+            self.swapper.swap(move || Box::new(graph.build(oscillator)));
+        }
+    }
+}
+
+// A mono saw bass with its own filter and envelope
+fn bass_voice(voice: &Voice, filter: &FilterParams, amp_env: &AdsrParams) -> Net64 {
+    let gate = || var(&voice.gate) | var(&voice.velocity);
+    let filter = (var(&voice.freq) >> saw() | var(filter.cutoff.shared()) | var(&voice.freq)) >> multi_filter(filter);
+    Net64::wrap(Box::new(filter * (gate() >> live_adsr(amp_env))))
+}
+
+// A stereo pad from the unison oscillator, with one filter per side
+fn pad_voice(voice: &Voice, osc: &OscillatorParams, filter: &FilterParams, amp_env: &AdsrParams) -> Net64 {
+    let side = || (pass() | var(filter.cutoff.shared()) | var(&voice.freq)) >> multi_filter(filter);
+    let env = (var(&voice.gate) | var(&voice.velocity)) >> live_adsr(amp_env);
+    Net64::wrap(Box::new((var(&voice.freq) >> unison_oscillator(osc) >> (side() | side())) * (env >> split::<U2>())))
+}
+
+// The instruments the app and the plugin have in common: the FM lead, the bass, the drums and the pad,
+// on the tracks "lead", "bass", "drums" and "pad". Both register the same parameter names, so a preset
+// sounds the same in either.
+pub struct Instruments {
+    pub voices: VoiceAllocator,
+    pub bass_voices: VoiceAllocator,
+    pub pad_voices: VoiceAllocator,
+    pub drums: DrumTriggers,
+    pub fm: FmParams,
+    pub filter: FilterParams,
+    pub lead: Lead,
+    // Modulates the parameters, its envelopes follow the lead
+    pub matrix: Arc<Mutex<ModMatrix>>,
+}
+
+impl Instruments {
+    pub fn new(registry: &ParamRegistry, mixer: &mut Mixer, bpm: &Shared<f64>, tuning: &Arc<Tuning>) -> Self {
+        let voices = VoiceAllocator::new(VOICES, tuning);
+        let fm = FmParams::new(registry, "fm");
+        let filter = FilterParams::new(registry, "filter");
+        let amp_env = AdsrParams::new(registry, "amp_env", 0.002, 0.001, 1.0, 0.1);
+        let filter_env = AdsrParams::new(registry, "filter_env", 0.01, 0.3, 0.3, 0.2);
+        let filter_env_amount = registry.add("filter_env.amount", 0.0, 6.0, 1.0);
+
+        let matrix = Arc::new(Mutex::new(ModMatrix::new(registry, voices.gate(), voices.velocity(), bpm)));
+        {
+            let mut matrix = matrix.lock().unwrap();
+            matrix.lfo_mut(0).settings.shape = LfoShape::Triangle;
+            matrix.lfo_mut(0).settings.sync = LfoSync::Beats(4.0);
+            matrix.route(0, ModSource::Lfo(0), "filter.cutoff").unwrap();
+            matrix.depth(0).set(0.1);
+        }
+
+        let lead_osc = OscillatorParams::new(registry, "lead.osc");
+        lead_osc.unison.set(3.0);
+        let graph = LeadGraph {
+            voices: voices.voices().to_vec(),
+            fm: fm.clone(),
+            osc: lead_osc,
+            filter: filter.clone(),
+            amp_env,
+            filter_env,
+            filter_env_amount,
+        };
+        let (synth, swapper) = hot_swap(Box::new(graph.build(LeadOscillator::default())));
+        let lead = Lead { graph, oscillator: Arc::new(Mutex::new(LeadOscillator::default())), swapper };
+        let lead_track = mixer.add_track(registry, "lead", Box::new(synth));
+        lead_track.send_a.set(0.2);
+
+        let bass_voices = VoiceAllocator::new(1, tuning);
+        let bass_filter = FilterParams::new(registry, "bass.filter");
+        bass_filter.mode.set(FilterMode::Ladder as usize as f64);
+        let bass_env = AdsrParams::new(registry, "bass.amp_env", 0.005, 0.2, 0.7, 0.08);
+        let bass = bass_voice(&bass_voices.voices()[0], &bass_filter, &bass_env);
+        mixer.add_track(registry, "bass", Box::new(bass * 0.3));
+
+        let drum_machine = DrumMachine::new(registry, "drums");
+        let drums = drum_machine.triggers();
+        mixer.add_track(registry, "drums", Box::new(An(drum_machine) * 0.5));
+
+        // A supersaw pad
+        let pad_voices = VoiceAllocator::new(VOICES, tuning);
+        let pad_osc = OscillatorParams::new(registry, "pad.osc");
+        pad_osc.unison.set(7.0);
+        let pad_filter = FilterParams::new(registry, "pad.filter");
+        pad_filter.cutoff.set(2500.0);
+        let pad_env = AdsrParams::new(registry, "pad.amp_env", 0.3, 0.5, 0.8, 0.8);
+        let pad = pad_voices.voices().iter()
+            .map(|voice| pad_voice(voice, &pad_osc, &pad_filter, &pad_env))
+            .reduce(|a, b| a + b)
+            .unwrap();
+        // Through the tremolo written in FAUST, its controls are "pad.tremolo.rate" and "pad.tremolo.depth"
+        let tremolo = FaustNode::<Tremolo>::new(registry, "pad.tremolo").expect("The tremolo fits in a FAUST node.");
+        let pad = pad >> Net64::wrap(Box::new(tremolo));
+        let pad_track = mixer.add_track(registry, "pad", Box::new(pad * 0.15));
+        pad_track.send_a.set(0.3);
+
+        Self { voices, bass_voices, pad_voices, drums, fm, filter, lead, matrix }
+    }
+
+    // Notes that are already sounding keep their pitch. Returns the old tunings of the allocators.
+    pub fn set_tuning(&mut self, tuning: &Arc<Tuning>) -> [Arc<Tuning>; 3] {
+        [&mut self.voices, &mut self.bass_voices, &mut self.pad_voices].map(|allocator| allocator.set_tuning(tuning))
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use fundsp::hacker::*;
+
+use super::params::{Param, ParamRegistry};
+
+// How far the limiter looks ahead, this is also the latency it adds
+const LOOKAHEAD: f64 = 0.005;
+// The soft clipper is linear up to this part of the ceiling and bends towards the ceiling above it
+const KNEE: f64 = 0.9;
+
+// Rounds off whatever is left above the knee, the output never goes past the ceiling
+fn soft_clip(x: f64, ceiling: f64) -> f64 {
+    let knee = KNEE * ceiling;
+    if x.abs() <= knee {
+        x
+    } else {
+        let headroom = ceiling - knee;
+        x.signum() * (knee + headroom * ((x.abs() - knee) / headroom).tanh())
+    }
+}
+
+// Brickwall limiter with lookahead and a soft clipper after it, to protect the speakers at the end of the master bus.
+// The gain needed to keep each frame under the ceiling is held for the lookahead time and then smoothed
+// with a moving average of the same length, so the gain is all the way down when the peak comes out of the delay.
+pub struct Limiter {
+    ceiling: Param,
+    release: Param,
+    bypass: Param,
+    // Gain reduction in dB, for the meter
+    reduction: Shared<f64>,
+    delay: VecDeque<(f64, f64)>,
+    // Frame number and gain of the frames that can still be the lowest gain in the window
+    minimum: VecDeque<(usize, f64)>,
+    average: VecDeque<f64>,
+    sum: f64,
+    frame: usize,
+    gain: f64,
+    length: usize,
+    sample_rate: f64,
+}
+
+impl Limiter {
+    // Registers "<prefix>.ceiling" (dB), "<prefix>.release" (seconds) and "<prefix>.bypass"
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        let mut limiter = Self {
+            ceiling: registry.add(&format!("{prefix}.ceiling"), -12.0, 0.0, -1.0),
+            release: registry.add(&format!("{prefix}.release"), 0.01, 1.0, 0.15),
+            bypass: registry.add(&format!("{prefix}.bypass"), 0.0, 1.0, 0.0),
+            reduction: shared(0.0),
+            delay: VecDeque::new(),
+            minimum: VecDeque::new(),
+            average: VecDeque::new(),
+            sum: 0.0,
+            frame: 0,
+            gain: 1.0,
+            length: 1,
+            sample_rate: DEFAULT_SR,
+        };
+        limiter.set_sample_rate(DEFAULT_SR);
+        limiter
+    }
+
+    pub fn reduction(&self) -> Shared<f64> {
+        self.reduction.clone()
+    }
+
+    // Allocates the buffers, so call this before the limiter is moved to the audio thread
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.length = std::cmp::max((LOOKAHEAD * sample_rate) as usize, 1);
+        self.delay = VecDeque::with_capacity(self.length);
+        self.minimum = VecDeque::with_capacity(self.length + 1);
+        self.average = VecDeque::with_capacity(self.length);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.delay.clear();
+        self.delay.extend(std::iter::repeat_n((0.0, 0.0), self.length - 1));
+        self.minimum.clear();
+        self.average.clear();
+        self.average.extend(std::iter::repeat_n(1.0, self.length));
+        self.sum = self.length as f64;
+        self.gain = 1.0;
+    }
+
+    pub fn process(&mut self, left: f64, right: f64) -> (f64, f64) {
+        let ceiling = db_amp(self.ceiling.value());
+        let peak = left.abs().max(right.abs());
+        let needed = if peak > ceiling { ceiling / peak } else { 1.0 };
+
+        // Lowest needed gain of the last `length` frames
+        while self.minimum.back().is_some_and(|(_, gain)| *gain >= needed) {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.frame, needed));
+        while self.minimum.front().is_some_and(|(frame, _)| frame + self.length <= self.frame) {
+            self.minimum.pop_front();
+        }
+        self.frame += 1;
+        let held = self.minimum.front().map_or(1.0, |(_, gain)| *gain);
+
+        // Down at once, back up with the release time
+        if held < self.gain {
+            self.gain = held;
+        } else {
+            let coefficient = 1.0 - (-1.0 / (self.release.value() * self.sample_rate)).exp();
+            self.gain += (held - self.gain) * coefficient;
+        }
+        self.sum += self.gain - self.average.pop_front().unwrap_or(1.0);
+        self.average.push_back(self.gain);
+        let gain = (self.sum / self.length as f64).min(1.0);
+
+        // The delay stays in even when bypassed, so switching doesn't jump in time
+        self.delay.push_back((left, right));
+        let (left, right) = self.delay.pop_front().unwrap_or_default();
+        if self.bypass.value() >= 0.5 {
+            self.reduction.set_value(0.0);
+            return (left, right);
+        }
+        self.reduction.set_value(-amp_db(gain));
+        (soft_clip(left * gain, ceiling), soft_clip(right * gain, ceiling))
+    }
+}
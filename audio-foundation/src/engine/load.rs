@@ -0,0 +1,175 @@
+use std::{collections::VecDeque, sync::Arc, time::{Duration, Instant}};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Seconds of peak load the history keeps, one value per second
+const HISTORY: usize = 60;
+// Averaging time of the rolling load
+const AVERAGE_TIME: f64 = 1.0;
+
+// f64 in an atomic, the audio thread writes and everybody else reads
+#[derive(Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+// How busy the audio callback is. Load is the time spent in the callback divided by the time the
+// buffer lasts, so 1.0 (100%) means we only just made it.
+#[derive(Default)]
+pub struct LoadMeter {
+    load: AtomicF64,
+    // Highest load since the UI last looked
+    peak: AtomicF64,
+    callbacks: AtomicU64,
+    // Callbacks that took longer than their buffer lasts
+    overruns: AtomicU64,
+    // Callbacks that came later than the previous buffer ran out, the device played silence or garbage
+    xruns: AtomicU64,
+    // Errors reported by the stream
+    errors: AtomicU64,
+}
+
+impl LoadMeter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn load(&self) -> f64 {
+        self.load.get()
+    }
+
+    pub fn callbacks(&self) -> u64 {
+        self.callbacks.load(Ordering::Relaxed)
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    pub fn xruns(&self) -> u64 {
+        self.xruns.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn count_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn take_peak(&self) -> f64 {
+        f64::from_bits(self.peak.0.swap(0, Ordering::Relaxed))
+    }
+}
+
+// The audio thread end. Call begin at the top of the callback and end at the bottom.
+pub struct CallbackTimer {
+    meter: Arc<LoadMeter>,
+    sample_rate: f64,
+    // When the last callback started according to the stream, and how long its buffer lasts
+    last: Option<(cpal::StreamInstant, Duration)>,
+    started: Instant,
+    deadline: f64,
+}
+
+impl CallbackTimer {
+    pub fn new(meter: &Arc<LoadMeter>, sample_rate: f64) -> Self {
+        Self {
+            meter: Arc::clone(meter),
+            sample_rate,
+            last: None,
+            started: Instant::now(),
+            deadline: 0.0,
+        }
+    }
+
+    pub fn begin(&mut self, info: &cpal::OutputCallbackInfo, frames: usize) {
+        self.started = Instant::now();
+        self.deadline = frames as f64 / self.sample_rate;
+
+        let now = info.timestamp().callback;
+        if let Some((last, length)) = self.last
+            && let Some(since) = now.duration_since(&last)
+            && since > length.mul_f64(1.5) + Duration::from_millis(1)
+        {
+            self.meter.xruns.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last = Some((now, Duration::from_secs_f64(self.deadline)));
+    }
+
+    pub fn end(&mut self) {
+        if self.deadline <= 0.0 {
+            return;
+        }
+        let load = self.started.elapsed().as_secs_f64() / self.deadline;
+        if load > 1.0 {
+            self.meter.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        // One pole average, so the time constant doesn't depend on the buffer size
+        let coefficient = 1.0 - (-self.deadline / AVERAGE_TIME).exp();
+        let average = self.meter.load.get();
+        self.meter.load.set(average + (load - average) * coefficient);
+        if load > self.meter.peak.get() {
+            self.meter.peak.set(load);
+        }
+        self.meter.callbacks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// The UI end: collects the peak load of every second
+pub struct LoadHistory {
+    meter: Arc<LoadMeter>,
+    peaks: VecDeque<f64>,
+    current: f64,
+    second: Instant,
+}
+
+impl LoadHistory {
+    pub fn new(meter: &Arc<LoadMeter>) -> Self {
+        Self {
+            meter: Arc::clone(meter),
+            peaks: VecDeque::with_capacity(HISTORY),
+            current: 0.0,
+            second: Instant::now(),
+        }
+    }
+
+    // Call this often, returns true when a new second was added to the history
+    pub fn update(&mut self) -> bool {
+        self.current = self.current.max(self.meter.take_peak());
+        if self.second.elapsed() < Duration::from_secs(1) {
+            return false;
+        }
+        self.second = Instant::now();
+        if self.peaks.len() == HISTORY {
+            self.peaks.pop_front();
+        }
+        self.peaks.push_back(self.current);
+        self.current = 0.0;
+        true
+    }
+
+    pub fn peak(&self) -> f64 {
+        self.peaks.iter().cloned().fold(self.current, f64::max)
+    }
+
+    pub fn print(&self, history: bool) {
+        let meter = &self.meter;
+        println!(
+            "DSP load {:.0}%, peak {:.0}% | {} callbacks, {} overruns, {} xruns, {} stream errors",
+            meter.load() * 100.0, self.peak() * 100.0, meter.callbacks(), meter.overruns(), meter.xruns(), meter.errors(),
+        );
+        if history {
+            let peaks: Vec<String> = self.peaks.iter().map(|peak| format!("{:.0}", peak * 100.0)).collect();
+            println!("Peak load per second (%): {}", peaks.join(" "));
+        }
+    }
+}
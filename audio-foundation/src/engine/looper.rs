@@ -0,0 +1,148 @@
+use super::voice::NoteEvent;
+
+// A recorded note event and where it happens in the loop, in beats from the start of the loop
+#[derive(Clone, Copy, Debug)]
+struct LoopEvent {
+    beat: f64,
+    event: NoteEvent,
+}
+
+// Records played notes against the transport and plays them back every loop.
+// Each pass of recording is a layer on top of the ones before (overdub), and layers can be taken off
+// again from the top.
+pub struct Looper {
+    length: f64,
+    layers: Vec<Vec<LoopEvent>>,
+    recording: Option<Vec<LoopEvent>>,
+    // Grid in beats that new note ons snap to, None records them as played
+    quantize: Option<f64>,
+    // How far each held note was moved by the quantizer, so its note off moves with it and the length stays
+    shifts: [f64; 128],
+    // Notes held while recording, and notes started by playback, so nothing hangs
+    recording_held: Vec<u8>,
+    sounding: Vec<u8>,
+    position: Option<f64>,
+}
+
+impl Looper {
+    // The length of the loop is in beats, for example 4.0 for one bar of 4/4
+    pub fn new(length: f64) -> Self {
+        Self {
+            length,
+            layers: Vec::new(),
+            recording: None,
+            quantize: None,
+            shifts: [0.0; 128],
+            recording_held: Vec::new(),
+            sounding: Vec::new(),
+            position: None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn quantize(&self) -> Option<f64> {
+        self.quantize
+    }
+
+    pub fn set_quantize(&mut self, grid: Option<f64>) {
+        self.quantize = grid.filter(|grid| *grid > 0.0);
+    }
+
+    // Starts a new layer, the layers that are already there keep playing
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+        self.recording_held.clear();
+    }
+
+    // Keeps the new layer. Notes that are still held get their note off at the given transport position.
+    pub fn stop_recording(&mut self, beats: f64) {
+        let Some(mut layer) = self.recording.take() else { return };
+        let beat = beats.rem_euclid(self.length);
+        for note in self.recording_held.drain(..) {
+            layer.push(LoopEvent { beat: (beat + self.shifts[note as usize]).rem_euclid(self.length), event: NoteEvent::Off(note) });
+        }
+        if !layer.is_empty() {
+            self.layers.push(layer);
+        }
+    }
+
+    // Adds a note event that was just played at the given transport position to the layer that is being recorded
+    pub fn record(&mut self, beats: f64, event: NoteEvent) {
+        let Some(layer) = &mut self.recording else { return };
+        let beat = beats.rem_euclid(self.length);
+        let beat = match event {
+            NoteEvent::On(note, _) => {
+                let snapped = self.quantize.map_or(beat, |grid| (beat / grid).round() * grid);
+                self.shifts[note as usize] = snapped - beat;
+                self.recording_held.push(note);
+                snapped
+            },
+            NoteEvent::Off(note) => {
+                // A note off without a recorded note on would only cut off other layers
+                let Some(index) = self.recording_held.iter().position(|held| *held == note) else { return };
+                self.recording_held.remove(index);
+                beat + self.shifts[note as usize]
+            },
+        };
+        layer.push(LoopEvent { beat: beat.rem_euclid(self.length), event });
+    }
+
+    // Takes off the last layer and returns note offs for everything playback left sounding
+    pub fn undo(&mut self) -> Vec<NoteEvent> {
+        self.layers.pop();
+        self.silence()
+    }
+
+    pub fn clear(&mut self) -> Vec<NoteEvent> {
+        self.layers.clear();
+        self.recording = None;
+        self.recording_held.clear();
+        self.silence()
+    }
+
+    fn silence(&mut self) -> Vec<NoteEvent> {
+        self.sounding.drain(..).map(NoteEvent::Off).collect()
+    }
+
+    // Moves to a new transport position (in beats, it only ever grows) and returns the events that were passed
+    pub fn update(&mut self, beats: f64) -> Vec<NoteEvent> {
+        let position = beats.rem_euclid(self.length);
+        let Some(previous) = self.position.replace(position) else { return Vec::new() };
+
+        // The window of the loop we moved through, which can wrap around the end
+        let passed = |beat: f64| {
+            if previous <= position {
+                beat > previous && beat <= position
+            } else {
+                beat > previous || beat <= position
+            }
+        };
+        let mut events: Vec<(f64, NoteEvent)> = self.layers.iter()
+            .flatten()
+            .filter(|event| passed(event.beat))
+            // Order by the distance from where we were, so a wrap plays the end of the loop first
+            .map(|event| ((event.beat - previous).rem_euclid(self.length), event.event))
+            .collect();
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut output = Vec::with_capacity(events.len());
+        for (_, event) in events {
+            match event {
+                NoteEvent::On(note, _) => self.sounding.push(note),
+                NoteEvent::Off(note) => {
+                    let Some(index) = self.sounding.iter().position(|sounding| *sounding == note) else { continue };
+                    self.sounding.remove(index);
+                },
+            }
+            output.push(event);
+        }
+        output
+    }
+}
@@ -0,0 +1,220 @@
+use std::f64::consts::FRAC_PI_2;
+use fundsp::hacker::*;
+
+use super::effects::{Delay, Effect, EffectsChain, Reverb};
+use super::limiter::Limiter;
+use super::params::{Param, ParamRegistry};
+
+// Gains follow their parameters with this coefficient per sample, so mute and solo don't click
+const SMOOTHING: f64 = 0.002;
+// Most channel pairs we can feed, 16 channels
+pub const OUTPUT_PAIRS: usize = 8;
+
+// Where a track or a return goes. 0 is the master bus, 1 and up are the output channel pairs
+// 3/4, 5/6 and so on, straight out without the master effects.
+fn output_pair(param: &Param) -> usize {
+    param.value().round().clamp(0.0, (OUTPUT_PAIRS - 1) as f64) as usize
+}
+
+// Adds a stereo signal to an output pair, pairs the device doesn't have are dropped
+fn add_to(outputs: &mut [(f64, f64)], pair: usize, (left, right): (f64, f64)) {
+    if let Some(output) = outputs.get_mut(pair) {
+        output.0 += left;
+        output.1 += right;
+    }
+}
+
+#[derive(Clone)]
+pub struct TrackParams {
+    pub volume: Param,
+    // -1.0 is hard left, 1.0 is hard right
+    pub pan: Param,
+    pub mute: Param,
+    pub solo: Param,
+    // Post fader sends to the two return buses
+    pub send_a: Param,
+    pub send_b: Param,
+    pub output: Param,
+}
+
+impl TrackParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            volume: registry.add(&format!("{prefix}.volume"), 0.0, 1.5, 1.0),
+            pan: registry.add(&format!("{prefix}.pan"), -1.0, 1.0, 0.0),
+            mute: registry.add(&format!("{prefix}.mute"), 0.0, 1.0, 0.0),
+            solo: registry.add(&format!("{prefix}.solo"), 0.0, 1.0, 0.0),
+            send_a: registry.add(&format!("{prefix}.send_a"), 0.0, 1.0, 0.0),
+            send_b: registry.add(&format!("{prefix}.send_b"), 0.0, 1.0, 0.0),
+            output: registry.add(&format!("{prefix}.output"), 0.0, (OUTPUT_PAIRS - 1) as f64, 0.0),
+        }
+    }
+}
+
+// One instrument on its own channel strip. The source has no inputs and one (mono) or two (stereo) outputs.
+struct Track {
+    source: Box<dyn AudioUnit64>,
+    params: TrackParams,
+    gain: f64,
+}
+
+impl Track {
+    // Returns the track after fader and pan, the sends are taken from this
+    fn process(&mut self, any_solo: bool) -> (f64, f64) {
+        let (left, right) = self.source.get_stereo();
+
+        let audible = if any_solo { self.params.solo.value() >= 0.5 } else { self.params.mute.value() < 0.5 };
+        let target = if audible { self.params.volume.value() } else { 0.0 };
+        self.gain += (target - self.gain) * SMOOTHING;
+
+        // Equal power panning, scaled so a centered track keeps its level
+        let angle = (self.params.pan.value() + 1.0) * 0.5 * FRAC_PI_2;
+        (left * self.gain * angle.cos() * 2.0_f64.sqrt(), right * self.gain * angle.sin() * 2.0_f64.sqrt())
+    }
+}
+
+// A shared effect that is fed by the track sends and comes back 100% wet
+struct ReturnBus {
+    effect: Box<dyn Effect>,
+    level: Param,
+    output: Param,
+    input: (f64, f64),
+}
+
+impl ReturnBus {
+    fn new(registry: &ParamRegistry, prefix: &str, effect: Box<dyn Effect>) -> Self {
+        Self {
+            effect,
+            level: registry.add(&format!("{prefix}.level"), 0.0, 1.0, 0.8),
+            output: registry.add(&format!("{prefix}.output"), 0.0, (OUTPUT_PAIRS - 1) as f64, 0.0),
+            input: (0.0, 0.0),
+        }
+    }
+
+    fn process(&mut self) -> (f64, f64) {
+        let (left, right) = self.effect.process(self.input.0, self.input.1);
+        self.input = (0.0, 0.0);
+        let level = self.level.value();
+        (left * level, right * level)
+    }
+}
+
+// Tracks are summed together with the two returns into the master bus,
+// which runs through the effects chain, the master volume and the limiter.
+// Tracks and returns can also skip the master bus and go to an output pair of their own. Those pairs have
+// limiters too, with the settings of the master limiter, so the cue and the other outputs that can end up
+// in headphones are protected as well. The lookahead of those limiters keeps them in time with the master.
+pub struct Mixer {
+    tracks: Vec<Track>,
+    returns: [ReturnBus; 2],
+    master: EffectsChain,
+    volume: Param,
+    limiter: Limiter,
+    // One for each output pair above 0, in the order of the pairs
+    pair_limiters: Vec<Limiter>,
+    // The output pair of the master bus, 0 is channels 1/2
+    output: Param,
+    sample_rate: f64,
+}
+
+impl Mixer {
+    pub fn new(registry: &ParamRegistry, bpm: &Shared<f64>) -> Self {
+        let reverb = Box::new(Reverb::new(registry, "return.a"));
+        let delay = Box::new(Delay::new(registry, "return.b", bpm));
+        Self {
+            tracks: Vec::new(),
+            returns: [
+                ReturnBus::new(registry, "return.a", reverb),
+                ReturnBus::new(registry, "return.b", delay),
+            ],
+            master: EffectsChain::new(registry, "fx", bpm),
+            volume: registry.add("master.volume", 0.0, 1.5, 1.0),
+            limiter: Limiter::new(registry, "master.limiter"),
+            // The same names give them the params of the master limiter
+            pair_limiters: (1..OUTPUT_PAIRS).map(|_| Limiter::new(registry, "master.limiter")).collect(),
+            output: registry.add("master.output", 0.0, (OUTPUT_PAIRS - 1) as f64, 0.0),
+            sample_rate: DEFAULT_SR,
+        }
+    }
+
+    // Allocates all delay lines, so call this before the mixer is moved to the audio thread
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        for track in self.tracks.iter_mut() {
+            track.source.set_sample_rate(sample_rate);
+        }
+        for bus in self.returns.iter_mut() {
+            bus.effect.set_sample_rate(sample_rate);
+        }
+        self.master.set_sample_rate(sample_rate);
+        self.limiter.set_sample_rate(sample_rate);
+        for limiter in self.pair_limiters.iter_mut() {
+            limiter.set_sample_rate(sample_rate);
+        }
+    }
+
+    // The track parameters are registered as "track.<name>.volume" and so on
+    pub fn add_track(&mut self, registry: &ParamRegistry, name: &str, mut source: Box<dyn AudioUnit64>) -> TrackParams {
+        source.set_sample_rate(self.sample_rate);
+        let params = TrackParams::new(registry, &format!("track.{name}"));
+        self.tracks.push(Track {
+            source,
+            params: params.clone(),
+            gain: 0.0,
+        });
+        params
+    }
+
+    // Gain reduction of the master limiter in dB
+    pub fn limiter_reduction(&self) -> Shared<f64> {
+        self.limiter.reduction()
+    }
+
+    pub fn master_mut(&mut self) -> &mut EffectsChain {
+        &mut self.master
+    }
+
+    // Mixes one frame into the output pairs, one pair for every two channels of the device.
+    // Returns the master bus, for the meters and the recorder.
+    pub fn process(&mut self, outputs: &mut [(f64, f64)]) -> (f64, f64) {
+        outputs.fill((0.0, 0.0));
+        let any_solo = self.tracks.iter().any(|track| track.params.solo.value() >= 0.5);
+
+        let mut sum = (0.0, 0.0);
+        for track in self.tracks.iter_mut() {
+            let (left, right) = track.process(any_solo);
+            match output_pair(&track.params.output) {
+                0 => {
+                    sum.0 += left;
+                    sum.1 += right;
+                },
+                pair => add_to(outputs, pair, (left, right)),
+            }
+            let sends = [track.params.send_a.value(), track.params.send_b.value()];
+            for (bus, send) in self.returns.iter_mut().zip(sends) {
+                bus.input.0 += left * send;
+                bus.input.1 += right * send;
+            }
+        }
+        for bus in self.returns.iter_mut() {
+            let (left, right) = bus.process();
+            match output_pair(&bus.output) {
+                0 => {
+                    sum.0 += left;
+                    sum.1 += right;
+                },
+                pair => add_to(outputs, pair, (left, right)),
+            }
+        }
+
+        let (left, right) = self.master.process(sum.0, sum.1);
+        let volume = self.volume.value();
+        let master = self.limiter.process(left * volume, right * volume);
+        add_to(outputs, output_pair(&self.output), master);
+        // Only the pairs the device has, a master that went to one of them is limited a second time with the rest
+        for (output, limiter) in outputs.iter_mut().skip(1).zip(self.pair_limiters.iter_mut()) {
+            *output = limiter.process(output.0, output.1);
+        }
+        master
+    }
+}
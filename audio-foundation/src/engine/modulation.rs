@@ -0,0 +1,318 @@
+use std::f64::consts::PI;
+use fundsp::hacker::*;
+use serde::{Deserialize, Serialize};
+
+use super::envelope::{Adsr, AdsrParams};
+use super::noise::Noise;
+use super::params::{Param, ParamRegistry};
+
+pub const LFO_COUNT: usize = 4;
+pub const ENVELOPE_COUNT: usize = 2;
+pub const SLOT_COUNT: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LfoShape {
+    #[default]
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleAndHold,
+}
+
+// Free running LFOs use their rate parameter in Hz,
+// synced LFOs take the length of one cycle in beats from the current tempo
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LfoSync {
+    #[default]
+    Free,
+    Beats(f64),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub sync: LfoSync,
+    // Phase offset from 0.0 to 1.0
+    pub phase: f64,
+    // Restart the cycle whenever the gate opens
+    pub retrigger: bool,
+}
+
+pub struct Lfo {
+    pub settings: LfoSettings,
+    rate: Param,
+    phase: f64,
+    held: f64,
+    noise: Noise,
+}
+
+impl Lfo {
+    fn new(rate: Param, seed: u32) -> Self {
+        Self {
+            settings: LfoSettings::default(),
+            rate,
+            phase: 0.0,
+            held: 0.0,
+            noise: Noise::new(seed),
+        }
+    }
+
+    pub fn retrigger(&mut self) {
+        self.phase = 0.0;
+    }
+
+    // Returns a bipolar value from -1.0 to 1.0
+    fn next(&mut self, dt: f64, bpm: f64) -> f64 {
+        let frequency = match self.settings.sync {
+            LfoSync::Free => self.rate.value(),
+            LfoSync::Beats(beats) => bpm / 60.0 / beats.max(1.0 / 64.0),
+        };
+        let phase = (self.phase + self.settings.phase).rem_euclid(1.0);
+        let value = match self.settings.shape {
+            LfoShape::Sine => (phase * 2.0 * PI).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            LfoShape::SampleAndHold => self.held,
+        };
+
+        self.phase += frequency * dt;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            // Pick a new random value once per cycle
+            self.held = self.noise.next();
+        }
+        value
+    }
+}
+
+// An extra ADSR that only exists to modulate, its stages are parameters as well
+struct ModEnvelope {
+    adsr: Adsr,
+    params: AdsrParams,
+}
+
+impl ModEnvelope {
+    fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            adsr: Adsr::default(),
+            params: AdsrParams::new(registry, prefix, 0.01, 0.3, 0.5, 0.5),
+        }
+    }
+
+    fn next(&mut self, velocity: f64, dt: f64) -> f64 {
+        self.params.update(&mut self.adsr);
+        self.adsr.next(dt) * self.params.velocity_scale(velocity)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModSource {
+    Lfo(usize),
+    Envelope(usize),
+}
+
+// One row of the matrix. The depth is a registered parameter ("mod1.depth", ...)
+// so it can be set from MIDI and be the destination of another slot.
+struct ModSlot {
+    source: Option<ModSource>,
+    destination: Option<Param>,
+    depth: Param,
+    target: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModRouting {
+    pub slot: usize,
+    pub source: ModSource,
+    pub destination: String,
+}
+
+// Everything a preset needs to restore the matrix, the depths are stored with the other parameters
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModMatrixSettings {
+    pub lfos: Vec<LfoSettings>,
+    pub routings: Vec<ModRouting>,
+}
+
+pub struct ModMatrix {
+    registry: ParamRegistry,
+    lfos: Vec<Lfo>,
+    envelopes: Vec<ModEnvelope>,
+    slots: Vec<ModSlot>,
+    gate: Shared<f64>,
+    velocity: Shared<f64>,
+    bpm: Shared<f64>,
+    // Every parameter that is modulated by at least one slot, with its summed offset
+    targets: Vec<(Param, f64)>,
+    lfo_values: [f64; LFO_COUNT],
+    envelope_values: [f64; ENVELOPE_COUNT],
+    last_gate: f64,
+}
+
+impl ModMatrix {
+    // The gate and velocity of the last played note drive the envelopes (and retrigger LFOs)
+    // like the inputs of LiveAdsr, bpm is used by synced LFOs
+    pub fn new(registry: &ParamRegistry, gate: &Shared<f64>, velocity: &Shared<f64>, bpm: &Shared<f64>) -> Self {
+        let lfos = (0..LFO_COUNT)
+            .map(|i| Lfo::new(registry.add(&format!("lfo{}.rate", i + 1), 0.01, 20.0, 1.0), 0x9E37_79B9 ^ (i as u32 + 1)))
+            .collect();
+        let envelopes = (0..ENVELOPE_COUNT)
+            .map(|i| ModEnvelope::new(registry, &format!("env{}", i + 1)))
+            .collect();
+        let slots = (0..SLOT_COUNT)
+            .map(|i| ModSlot {
+                source: None,
+                destination: None,
+                depth: registry.add(&format!("mod{}.depth", i + 1), -1.0, 1.0, 0.0),
+                target: 0,
+            })
+            .collect();
+
+        Self {
+            registry: registry.clone(),
+            lfos,
+            envelopes,
+            slots,
+            gate: gate.clone(),
+            velocity: velocity.clone(),
+            bpm: bpm.clone(),
+            targets: Vec::new(),
+            lfo_values: [0.0; LFO_COUNT],
+            envelope_values: [0.0; ENVELOPE_COUNT],
+            last_gate: 0.0,
+        }
+    }
+
+    pub fn lfo_mut(&mut self, index: usize) -> &mut Lfo {
+        &mut self.lfos[index]
+    }
+
+    pub fn depth(&self, slot: usize) -> &Param {
+        &self.slots[slot].depth
+    }
+
+    pub fn route(&mut self, slot: usize, source: ModSource, destination: &str) -> Result<(), String> {
+        if slot >= SLOT_COUNT {
+            return Err(format!("There is no modulation slot {slot}"));
+        }
+        let valid = match source {
+            ModSource::Lfo(i) => i < LFO_COUNT,
+            ModSource::Envelope(i) => i < ENVELOPE_COUNT,
+        };
+        if !valid {
+            return Err(format!("There is no modulation source {source:?}"));
+        }
+        let param = self.registry.get(destination).ok_or(format!("Unknown parameter: {destination}"))?;
+
+        self.slots[slot].source = Some(source);
+        self.slots[slot].destination = Some(param);
+        self.update_targets();
+        Ok(())
+    }
+
+    pub fn clear(&mut self, slot: usize) {
+        if let Some(slot) = self.slots.get_mut(slot) {
+            slot.source = None;
+            slot.destination = None;
+        }
+        self.update_targets();
+    }
+
+    fn update_targets(&mut self) {
+        for (param, _) in self.targets.drain(..) {
+            param.clear_modulation();
+        }
+        for slot in self.slots.iter_mut() {
+            if let Some(param) = &slot.destination {
+                slot.target = match self.targets.iter().position(|(p, _)| p.name() == param.name()) {
+                    Some(index) => index,
+                    None => {
+                        self.targets.push((param.clone(), 0.0));
+                        self.targets.len() - 1
+                    },
+                };
+            }
+        }
+    }
+
+    // Called from the audio thread once per block, dt is the length of the block in seconds
+    pub fn tick(&mut self, dt: f64) {
+        let gate = self.gate.value();
+        if gate > 0.0 && gate != self.last_gate {
+            for lfo in self.lfos.iter_mut().filter(|lfo| lfo.settings.retrigger) {
+                lfo.retrigger();
+            }
+            for envelope in self.envelopes.iter_mut() {
+                envelope.adsr.retrigger();
+            }
+        } else if gate <= 0.0 {
+            for envelope in self.envelopes.iter_mut() {
+                envelope.adsr.set_gate(false);
+            }
+        }
+        self.last_gate = gate;
+
+        let bpm = self.bpm.value();
+        let velocity = self.velocity.value();
+        for (value, lfo) in self.lfo_values.iter_mut().zip(self.lfos.iter_mut()) {
+            *value = lfo.next(dt, bpm);
+        }
+        for (value, envelope) in self.envelope_values.iter_mut().zip(self.envelopes.iter_mut()) {
+            *value = envelope.next(velocity, dt);
+        }
+
+        for (_, offset) in self.targets.iter_mut() {
+            *offset = 0.0;
+        }
+        for slot in &self.slots {
+            let source = match slot.source {
+                Some(ModSource::Lfo(i)) => self.lfo_values[i],
+                Some(ModSource::Envelope(i)) => self.envelope_values[i],
+                None => continue,
+            };
+            self.targets[slot.target].1 += source * slot.depth.value();
+        }
+        for (param, offset) in &self.targets {
+            param.modulate(*offset);
+        }
+    }
+
+    pub fn settings(&self) -> ModMatrixSettings {
+        ModMatrixSettings {
+            lfos: self.lfos.iter().map(|lfo| lfo.settings).collect(),
+            routings: self.slots.iter().enumerate().filter_map(|(i, slot)| {
+                Some(ModRouting {
+                    slot: i,
+                    source: slot.source?,
+                    destination: slot.destination.as_ref()?.name().to_string(),
+                })
+            }).collect(),
+        }
+    }
+
+    // Routings to parameters that don't exist are reported and skipped
+    pub fn apply(&mut self, settings: &ModMatrixSettings) {
+        for (lfo, lfo_settings) in self.lfos.iter_mut().zip(settings.lfos.iter()) {
+            lfo.settings = *lfo_settings;
+        }
+        for slot in 0..SLOT_COUNT {
+            self.clear(slot);
+        }
+        for routing in &settings.routings {
+            if let Err(err) = self.route(routing.slot, routing.source, &routing.destination) {
+                eprintln!("Skipping modulation routing: {err}");
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        for (i, slot) in self.slots.iter().enumerate() {
+            if let (Some(source), Some(param)) = (slot.source, &slot.destination) {
+                println!("Slot {}: {:?} -> {} (depth {:.2})", i + 1, source, param.name(), slot.depth.value());
+            }
+        }
+    }
+}
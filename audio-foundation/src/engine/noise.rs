@@ -0,0 +1,25 @@
+// Xorshift white noise, cheap enough to run per sample and the same every time for a given seed.
+// The seed must not be 0, that stays 0 forever.
+#[derive(Clone)]
+pub(super) struct Noise {
+    seed: u32,
+}
+
+impl Noise {
+    pub(super) fn new(seed: u32) -> Self {
+        Self { seed }
+    }
+
+    // Uniform between 0.0 and 1.0
+    pub(super) fn unipolar(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f64 / u32::MAX as f64
+    }
+
+    // Uniform between -1.0 and 1.0
+    pub(super) fn next(&mut self) -> f64 {
+        self.unipolar() * 2.0 - 1.0
+    }
+}
@@ -0,0 +1,200 @@
+use std::f64::consts::FRAC_PI_2;
+use fundsp::hacker::*;
+
+use super::params::{Param, ParamRegistry};
+
+// Most unison voices per note, a supersaw uses 7
+pub const MAX_UNISON: usize = 7;
+// Highest phase step per sample, a bit below Nyquist. Above that the phase steps make no sense and
+// run() would have to correct more than one cycle per sample.
+const MAX_DT: f64 = 0.45;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Waveform {
+    #[default]
+    Saw,
+    Pulse,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 2] = [Waveform::Saw, Waveform::Pulse];
+
+    pub fn from_value(value: f64) -> Self {
+        let index = value.round().clamp(0.0, (Self::ALL.len() - 1) as f64);
+        Self::ALL[index as usize]
+    }
+
+    // Naive value at a phase from 0.0 to 1.0
+    fn value(&self, phase: f64, width: f64) -> f64 {
+        match self {
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Pulse => if phase < width { 1.0 } else { -1.0 },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OscillatorParams {
+    pub waveform: Param,
+    // Number of detuned copies of the oscillator, spread out over the stereo field
+    pub unison: Param,
+    // Distance between the lowest and the highest unison voice in cents
+    pub detune: Param,
+    pub spread: Param,
+    // Part of the cycle the pulse is high, modulate it for PWM
+    pub pulse_width: Param,
+    // With sync on the oscillator restarts with every cycle of the note and runs at sync_ratio times its pitch
+    pub sync: Param,
+    pub sync_ratio: Param,
+}
+
+impl OscillatorParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            waveform: registry.add(&format!("{prefix}.waveform"), 0.0, (Waveform::ALL.len() - 1) as f64, 0.0),
+            unison: registry.add(&format!("{prefix}.unison"), 1.0, MAX_UNISON as f64, 1.0),
+            detune: registry.add(&format!("{prefix}.detune"), 0.0, 100.0, 20.0),
+            spread: registry.add(&format!("{prefix}.spread"), 0.0, 1.0, 0.7),
+            pulse_width: registry.add(&format!("{prefix}.pulse_width"), 0.05, 0.95, 0.5),
+            sync: registry.add(&format!("{prefix}.sync"), 0.0, 1.0, 0.0),
+            sync_ratio: registry.add(&format!("{prefix}.sync_ratio"), 1.0, 8.0, 2.0),
+        }
+    }
+}
+
+// One of the unison copies. The output is one sample late, so the correction for a jump in the
+// waveform (PolyBLEP) can go on the samples before and after it.
+#[derive(Clone, Default)]
+struct UnisonVoice {
+    phase: f64,
+    // Phase of the note itself, the oscillator restarts when it wraps if sync is on
+    master: f64,
+    previous: f64,
+}
+
+impl UnisonVoice {
+    // Advances the phase by `delta` starting `start` samples into this sample, and corrects the jumps it passes.
+    // Returns the new phase.
+    fn run(&mut self, start: f64, delta: f64, dt: f64, waveform: Waveform, width: f64, next: &mut f64) -> f64 {
+        let end = self.phase + delta;
+        // The jumps of the waveform in one cycle: the wrap at 1.0 and the falling edge of the pulse,
+        // which can also come after the wrap
+        let edges = match waveform {
+            Waveform::Saw => [(1.0, -2.0), (f64::INFINITY, 0.0), (f64::INFINITY, 0.0)],
+            Waveform::Pulse => [(width, -2.0), (1.0, 2.0), (width + 1.0, -2.0)],
+        };
+        for (edge, height) in edges {
+            if self.phase < edge && end >= edge {
+                let since = 1.0 - (start + (edge - self.phase) / dt);
+                self.blep(since, height, next);
+            }
+        }
+        end.fract()
+    }
+
+    // Smooths a jump of the given height that happened `since` samples before the current sample
+    fn blep(&mut self, since: f64, height: f64, next: &mut f64) {
+        let t = since.clamp(0.0, 1.0);
+        self.previous += height * 0.5 * t * t;
+        *next -= height * 0.5 * (1.0 - t) * (1.0 - t);
+    }
+
+    fn tick(&mut self, dt: f64, sync: Option<f64>, waveform: Waveform, width: f64) -> f64 {
+        let mut next = 0.0;
+        match sync {
+            Some(ratio) => {
+                // The synced oscillator runs faster than the note, it has to stay below Nyquist as well
+                let slave_dt = (dt * ratio).min(MAX_DT);
+                self.master += dt;
+                if self.master >= 1.0 {
+                    self.master -= 1.0;
+                    // The note cycle ended `since` samples ago, run up to there and restart from zero
+                    let since = (self.master / dt).clamp(0.0, 1.0);
+                    let phase = self.run(0.0, (1.0 - since) * slave_dt, slave_dt, waveform, width, &mut next);
+                    let jump = waveform.value(0.0, width) - waveform.value(phase, width);
+                    self.blep(since, jump, &mut next);
+                    self.phase = 0.0;
+                    self.phase = self.run(1.0 - since, since * slave_dt, slave_dt, waveform, width, &mut next);
+                } else {
+                    self.phase = self.run(0.0, slave_dt, slave_dt, waveform, width, &mut next);
+                }
+            },
+            None => {
+                self.phase = self.run(0.0, dt, dt, waveform, width, &mut next);
+            },
+        }
+        let output = self.previous;
+        self.previous = waveform.value(self.phase, width) + next;
+        output
+    }
+}
+
+// Saw and pulse oscillator with unison, hard sync and pulse width, all read live from the parameters.
+// - Input 0: frequency in Hz.
+// - Output 0: left.
+// - Output 1: right.
+#[derive(Clone)]
+pub struct UnisonOscillator {
+    params: OscillatorParams,
+    voices: [UnisonVoice; MAX_UNISON],
+    sample_rate: f64,
+}
+
+impl UnisonOscillator {
+    pub fn new(params: &OscillatorParams) -> Self {
+        let mut oscillator = Self {
+            params: params.clone(),
+            voices: Default::default(),
+            sample_rate: DEFAULT_SR,
+        };
+        oscillator.reset();
+        oscillator
+    }
+}
+
+impl AudioNode for UnisonOscillator {
+    const ID: u64 = 0x4f58_0006;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U2;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        // Start the copies at different phases, together they would sound like one loud oscillator at first
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            *voice = UnisonVoice { phase: (i as f64 * 0.618).fract(), ..Default::default() };
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U2> {
+        let waveform = Waveform::from_value(self.params.waveform.value());
+        let width = self.params.pulse_width.value();
+        let sync = (self.params.sync.value() >= 0.5).then(|| self.params.sync_ratio.value());
+        let count = self.params.unison.value().round().clamp(1.0, MAX_UNISON as f64) as usize;
+        let detune = self.params.detune.value();
+        let spread = self.params.spread.value();
+        let freq = input[0].max(0.0);
+
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, voice) in self.voices[..count].iter_mut().enumerate() {
+            // -1.0 for the lowest copy to 1.0 for the highest, they are panned the same way
+            let position = if count > 1 { i as f64 / (count - 1) as f64 * 2.0 - 1.0 } else { 0.0 };
+            let dt = (freq * exp2(position * detune * 0.5 / 1200.0) / self.sample_rate).min(MAX_DT);
+            let x = voice.tick(dt, sync, waveform, width);
+            let angle = (position * spread + 1.0) * 0.5 * FRAC_PI_2;
+            left += x * angle.cos();
+            right += x * angle.sin();
+        }
+        // About the same loudness for any number of copies
+        let gain = 1.0 / (count as f64).sqrt();
+        [left * gain, right * gain].into()
+    }
+}
+
+pub fn unison_oscillator(params: &OscillatorParams) -> An<UnisonOscillator> {
+    An(UnisonOscillator::new(params))
+}
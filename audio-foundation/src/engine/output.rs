@@ -0,0 +1,153 @@
+use std::{error::Error, time::{Duration, Instant}};
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use super::load::LoadMeter;
+
+// Fills one buffer of interleaved frames with the given number of channels
+pub type Render = Box<dyn FnMut(&mut [f32], usize, &cpal::OutputCallbackInfo) + Send>;
+
+// Time between attempts to bring a broken stream back
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// A stream that hasn't called us for this long is considered dead, some backends never report an error
+const WATCHDOG: Duration = Duration::from_secs(2);
+
+pub fn device_name(device: &cpal::Device) -> String {
+    device.name().unwrap_or_else(|_| "unknown device".to_string())
+}
+
+// The output device whose name contains the given text, or the default device
+pub fn find_output_device(name: Option<&str>) -> Option<cpal::Device> {
+    let host = cpal::default_host();
+    name.and_then(|name| host.output_devices().ok()?.find(|device| device_name(device).contains(name)))
+        .or_else(|| host.default_output_device())
+}
+
+// The output stream and the device it plays on. The render function (and with it the whole synth)
+// lives outside of the stream, so the stream can be rebuilt on another device without losing any state.
+pub struct AudioOutput {
+    render: Arc<Mutex<Render>>,
+    sample_rate: u32,
+    meter: Arc<LoadMeter>,
+    stream: Option<cpal::Stream>,
+    device: String,
+    // Set from the error callback when the device went away
+    failed: Arc<AtomicBool>,
+    last_attempt: Instant,
+    // Callback count at the last watchdog check
+    callbacks: u64,
+    last_callback: Instant,
+}
+
+impl AudioOutput {
+    pub fn new(render: Render, sample_rate: f64, meter: &Arc<LoadMeter>) -> Self {
+        Self {
+            render: Arc::new(Mutex::new(render)),
+            sample_rate: sample_rate as u32,
+            meter: Arc::clone(meter),
+            stream: None,
+            device: String::new(),
+            failed: Arc::new(AtomicBool::new(false)),
+            last_attempt: Instant::now(),
+            callbacks: 0,
+            last_callback: Instant::now(),
+        }
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    // Plays on the given device. The device has to run at our sample rate, everything is set up for that.
+    pub fn start(&mut self, device: &cpal::Device) -> Result<(), Box<dyn Error>> {
+        // Drop the old stream first, some backends only allow one stream per device
+        self.stream = None;
+        let name = device_name(device);
+        let config = device.supported_output_configs()?
+            .filter(|config| config.sample_format() == cpal::SampleFormat::F32)
+            .find(|config| (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&self.sample_rate))
+            .ok_or_else(|| format!("{name} can't play f32 at {} Hz", self.sample_rate))?
+            .with_sample_rate(cpal::SampleRate(self.sample_rate));
+        let channels = config.channels() as usize;
+        let stream_config: cpal::StreamConfig = config.into();
+        println!("Output device: {name}");
+        println!("Stream config: {:#?}", stream_config);
+
+        let render = Arc::clone(&self.render);
+        let audio_callback = move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+            // Only stream callbacks take the lock. The old stream can still be in its last callback when the new one
+            // starts on a rebuild, the new one plays silence until it's done.
+            match render.try_lock() {
+                Ok(mut render) => render(data, channels, info),
+                Err(_) => data.fill(0.0),
+            }
+        };
+        let failed = Arc::clone(&self.failed);
+        let meter = Arc::clone(&self.meter);
+        let err_fn = move |err| {
+            meter.count_error();
+            eprintln!("An error occurred on the audio stream: {}", err);
+            if let cpal::StreamError::DeviceNotAvailable = err {
+                failed.store(true, Ordering::Relaxed);
+            }
+        };
+
+        let stream = device.build_output_stream(&stream_config, audio_callback, err_fn, None)?;
+        stream.play()?;
+        self.stream = Some(stream);
+        self.device = name;
+        self.failed.store(false, Ordering::Relaxed);
+        self.last_callback = Instant::now();
+        Ok(())
+    }
+
+    // Switches to the next output device in the list of the host
+    pub fn next_device(&mut self) -> Result<(), Box<dyn Error>> {
+        let devices: Vec<_> = cpal::default_host().output_devices()?.collect();
+        let current = devices.iter().position(|device| device_name(device) == self.device);
+        let start = current.map_or(0, |index| index + 1);
+        // Try the others in order, a device that can't run at our rate is skipped
+        for offset in 0..devices.len() {
+            let device = &devices[(start + offset) % devices.len()];
+            match self.start(device) {
+                Ok(()) => return Ok(()),
+                Err(err) => eprintln!("Skipping {}: {err}", device_name(device)),
+            }
+        }
+        Err("No output device is usable.".into())
+    }
+
+    // Call this regularly from the UI thread. When the stream died it is rebuilt, on the same device if it's
+    // still there and on the default device or any other one if not.
+    pub fn check(&mut self) {
+        let callbacks = self.meter.callbacks();
+        if callbacks != self.callbacks {
+            self.callbacks = callbacks;
+            self.last_callback = Instant::now();
+        }
+        let stalled = self.stream.is_some() && self.last_callback.elapsed() > WATCHDOG;
+        let broken = self.stream.is_none() || self.failed.load(Ordering::Relaxed) || stalled;
+        if !broken || self.last_attempt.elapsed() < RETRY_INTERVAL {
+            return;
+        }
+        self.last_attempt = Instant::now();
+        eprintln!("The audio stream on {} stopped, restarting it", self.device);
+        self.stream = None;
+
+        let host = cpal::default_host();
+        let mut candidates: Vec<cpal::Device> = Vec::new();
+        if let Ok(devices) = host.output_devices() {
+            let (same, others): (Vec<_>, Vec<_>) = devices.partition(|device| device_name(device) == self.device);
+            candidates.extend(same);
+            candidates.extend(host.default_output_device());
+            candidates.extend(others);
+        }
+        for device in candidates {
+            match self.start(&device) {
+                Ok(()) => return,
+                Err(err) => eprintln!("Could not play on {}: {err}", device_name(&device)),
+            }
+        }
+        eprintln!("No output device is usable, trying again");
+    }
+}
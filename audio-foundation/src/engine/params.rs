@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use fundsp::hacker::*;
+
+// A named synth parameter with a fixed range.
+// `base` is the value set by the user (keys, MIDI, presets) and `value` is what the audio graph reads.
+// Without modulation both are the same, the modulation matrix writes `base + offset` into `value`.
+#[derive(Clone)]
+pub struct Param {
+    name: String,
+    min: f64,
+    max: f64,
+    default: f64,
+    base: Shared<f64>,
+    value: Shared<f64>,
+}
+
+impl Param {
+    pub fn new(name: &str, min: f64, max: f64, default: f64) -> Self {
+        let default = default.clamp(min, max);
+        Self {
+            name: name.to_string(),
+            min,
+            max,
+            default,
+            base: shared(default),
+            value: shared(default),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn range(&self) -> (f64, f64) {
+        (self.min, self.max)
+    }
+
+    pub fn default_value(&self) -> f64 {
+        self.default
+    }
+
+    // The unmodulated value
+    pub fn get(&self) -> f64 {
+        self.base.value()
+    }
+
+    pub fn set(&self, value: f64) {
+        let value = value.clamp(self.min, self.max);
+        self.base.set_value(value);
+        self.value.set_value(value);
+    }
+
+    // Maps 0.0..1.0 (for example a MIDI knob) onto the range of the parameter
+    pub fn set_normalized(&self, x: f64) {
+        self.set(lerp(self.min, self.max, x.clamp(0.0, 1.0)));
+    }
+
+    // The value including modulation, this is what the synth hears
+    pub fn value(&self) -> f64 {
+        self.value.value()
+    }
+
+    // Offset is given as a fraction of the full parameter range
+    pub fn modulate(&self, offset: f64) {
+        let value = self.base.value() + offset * (self.max - self.min);
+        self.value.set_value(value.clamp(self.min, self.max));
+    }
+
+    pub fn clear_modulation(&self) {
+        self.value.set_value(self.base.value());
+    }
+
+    // Use this with var() to read the parameter inside a fundsp graph
+    pub fn shared(&self) -> &Shared<f64> {
+        &self.value
+    }
+}
+
+// All parameters of the synth by name.
+// Cloning the registry is cheap and every clone sees the same parameters.
+#[derive(Clone, Default)]
+pub struct ParamRegistry {
+    params: Arc<Mutex<Vec<Param>>>,
+    // MIDI CC number to parameter name
+    controllers: Arc<Mutex<BTreeMap<u8, String>>>,
+}
+
+impl ParamRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registering an existing name returns the parameter that is already there
+    pub fn add(&self, name: &str, min: f64, max: f64, default: f64) -> Param {
+        let mut params = self.params.lock().unwrap();
+        if let Some(param) = params.iter().find(|p| p.name == name) {
+            return param.clone();
+        }
+        let param = Param::new(name, min, max, default);
+        params.push(param.clone());
+        param
+    }
+
+    // Takes parameters out that don't pass, for the ones that come and go like the params of a patch.
+    // Whoever holds a clone of a removed parameter can still use it, it just isn't in the registry anymore.
+    pub fn retain(&self, mut keep: impl FnMut(&Param) -> bool) {
+        self.params.lock().unwrap().retain(|param| keep(param));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Param> {
+        self.params.lock().unwrap().iter().find(|p| p.name == name).cloned()
+    }
+
+    // All parameters in the order they were registered
+    pub fn params(&self) -> Vec<Param> {
+        self.params.lock().unwrap().clone()
+    }
+
+    pub fn values(&self) -> BTreeMap<String, f64> {
+        self.params.lock().unwrap().iter().map(|p| (p.name.clone(), p.get())).collect()
+    }
+
+    // Unknown names are skipped so presets from older versions still load
+    pub fn apply(&self, values: &BTreeMap<String, f64>) {
+        for param in self.params.lock().unwrap().iter() {
+            if let Some(value) = values.get(&param.name) {
+                param.set(*value);
+            }
+        }
+    }
+
+    pub fn map_cc(&self, cc: u8, name: &str) {
+        self.controllers.lock().unwrap().insert(cc, name.to_string());
+    }
+
+    // Sets the parameter mapped to this controller, value is the 0..127 MIDI value
+    pub fn handle_cc(&self, cc: u8, value: u8) -> Option<Param> {
+        let name = self.controllers.lock().unwrap().get(&cc)?.clone();
+        let param = self.get(&name)?;
+        param.set_normalized(value as f64 / 127.0);
+        Some(param)
+    }
+
+    pub fn print(&self) {
+        for param in self.params.lock().unwrap().iter() {
+            println!("{:<24} {:>10.3}  ({:.3})", param.name, param.get(), param.value());
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use fundsp::hacker::*;
+
+use super::envelope::{live_adsr, AdsrParams};
+use super::params::{Param, ParamRegistry};
+use super::voice::Voice;
+
+// Patches are text files that describe one voice, so they can be written without touching Rust:
+//
+//   # A saw through a lowpass, anything after # is a comment
+//   param cutoff 100 8000 1200        named parameter: min, max and default, registered as "<prefix>.cutoff"
+//   osc = saw freq                    a node: its name, its kind and what goes into its inputs
+//   env = adsr gate velocity
+//   filtered = lowpass osc cutoff 0.7
+//   voice = mul filtered env
+//   out voice                         one output for mono, two for left and right
+//
+// Inputs are numbers, params, nodes from lines above and the note: freq, gate and velocity.
+// Nodes can only use nodes above them, so there's no feedback.
+// The envelope of an adsr node has parameters of its own, "<prefix>.env.attack" for the one above.
+
+// The inputs of every patch, in this order
+const VOICE_INPUTS: [&str; 3] = ["freq", "gate", "velocity"];
+// Longest time a delay node can delay, in seconds
+const MAX_DELAY: f64 = 2.0;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Moog,
+    Adsr,
+    Delay,
+    Tanh,
+    Mul,
+    Add,
+    Sub,
+}
+
+impl Kind {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "sine" => Kind::Sine,
+            "saw" => Kind::Saw,
+            "square" => Kind::Square,
+            "triangle" => Kind::Triangle,
+            "noise" => Kind::Noise,
+            "lowpass" => Kind::Lowpass,
+            "highpass" => Kind::Highpass,
+            "bandpass" => Kind::Bandpass,
+            "moog" => Kind::Moog,
+            "adsr" => Kind::Adsr,
+            "delay" => Kind::Delay,
+            "tanh" => Kind::Tanh,
+            "mul" => Kind::Mul,
+            "add" => Kind::Add,
+            "sub" => Kind::Sub,
+            _ => return None,
+        })
+    }
+
+    // What goes into the node, for the error messages
+    fn inputs(&self) -> &'static [&'static str] {
+        match self {
+            Kind::Sine | Kind::Saw | Kind::Square | Kind::Triangle => &["freq"],
+            Kind::Noise => &[],
+            Kind::Lowpass | Kind::Highpass | Kind::Bandpass => &["signal", "cutoff", "q"],
+            Kind::Moog => &["signal", "cutoff", "resonance"],
+            Kind::Adsr => &["gate", "velocity"],
+            Kind::Delay => &["signal", "time"],
+            Kind::Tanh => &["signal"],
+            Kind::Mul | Kind::Add | Kind::Sub => &["a", "b"],
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Source {
+    Number(f64),
+    // Index into the params of the patch
+    Param(usize),
+    // One of VOICE_INPUTS
+    Voice(usize),
+    // Index into the nodes of the patch
+    Node(usize),
+}
+
+#[derive(Clone)]
+struct Node {
+    name: String,
+    kind: Kind,
+    inputs: Vec<Source>,
+    // The envelope of an adsr node, registered with the params once the whole patch is known to be fine
+    envelope: Option<Box<AdsrParams>>,
+}
+
+impl Node {
+    fn unit(&self) -> Box<dyn AudioUnit64> {
+        match self.kind {
+            Kind::Sine => Box::new(sine()),
+            Kind::Saw => Box::new(saw()),
+            Kind::Square => Box::new(square()),
+            Kind::Triangle => Box::new(triangle()),
+            Kind::Noise => Box::new(noise()),
+            Kind::Lowpass => Box::new(lowpass()),
+            Kind::Highpass => Box::new(highpass()),
+            Kind::Bandpass => Box::new(bandpass()),
+            Kind::Moog => Box::new(moog()),
+            Kind::Adsr => Box::new(live_adsr(self.envelope.as_ref().expect("a Patch has its envelopes registered"))),
+            Kind::Delay => Box::new(tap(0.0, MAX_DELAY)),
+            Kind::Tanh => Box::new(shape(Shape::Tanh(1.0))),
+            Kind::Mul => Box::new(pass() * pass()),
+            Kind::Add => Box::new(pass() + pass()),
+            Kind::Sub => Box::new(pass() - pass()),
+        }
+    }
+}
+
+// A param line: the name without the prefix, min, max and default
+struct ParamLine {
+    name: String,
+    min: f64,
+    max: f64,
+    default: f64,
+}
+
+// A parsed patch, checked and with its parameters registered. Building it can't go wrong anymore,
+// so that can happen on another thread.
+#[derive(Clone)]
+pub struct Patch {
+    nodes: Vec<Node>,
+    outputs: Vec<Source>,
+    params: Vec<Param>,
+}
+
+impl Patch {
+    pub fn load(path: impl AsRef<Path>, registry: &ParamRegistry, prefix: &str) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        Self::parse(&fs::read_to_string(path)?, registry, prefix).map_err(|err| format!("{}: {err}", path.display()).into())
+    }
+
+    // Nothing is registered unless the whole text is fine. The params of the patch are then made to match the
+    // text, see register.
+    pub fn parse(text: &str, registry: &ParamRegistry, prefix: &str) -> Result<Self, Box<dyn Error>> {
+        let mut param_lines = Vec::new();
+        let mut params: BTreeMap<String, usize> = BTreeMap::new();
+        let mut names: BTreeMap<String, usize> = BTreeMap::new();
+        let mut nodes = Vec::new();
+        let mut outputs = None;
+        for (number, line) in text.lines().enumerate().map(|(index, line)| (index + 1, line)) {
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let error = |message: String| -> Box<dyn Error> { format!("line {number}: {message}").into() };
+            let source = |word: &str| -> Result<Source, Box<dyn Error>> {
+                if let Ok(value) = word.parse::<f64>() && value.is_finite() {
+                    Ok(Source::Number(value))
+                } else if let Some(input) = VOICE_INPUTS.iter().position(|input| *input == word) {
+                    Ok(Source::Voice(input))
+                } else if let Some(param) = params.get(word) {
+                    Ok(Source::Param(*param))
+                } else if let Some(node) = names.get(word) {
+                    Ok(Source::Node(*node))
+                } else {
+                    Err(error(format!("\"{word}\" is not a number, a param, a node above this line or one of freq, gate and velocity")))
+                }
+            };
+            let new_name = |word: &str| -> Result<(), Box<dyn Error>> {
+                if word.is_empty() || !word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') || word.parse::<f64>().is_ok() {
+                    Err(error(format!("\"{word}\" can't be a name, use letters, digits and _")))
+                } else if VOICE_INPUTS.contains(&word) || params.contains_key(word) || names.contains_key(word) {
+                    Err(error(format!("\"{word}\" is already taken")))
+                } else {
+                    Ok(())
+                }
+            };
+            match words.as_slice() {
+                [] => (),
+                ["param", name, min, max, default] => {
+                    new_name(name)?;
+                    let [min, max, default] = [min, max, default].map(|word| word.parse::<f64>().ok().filter(|value| value.is_finite()));
+                    let (Some(min), Some(max), Some(default)) = (min, max, default) else {
+                        return Err(error("min, max and default of a param have to be numbers".to_string()));
+                    };
+                    if min >= max || default < min || default > max {
+                        return Err(error("a param needs min < max and the default between them".to_string()));
+                    }
+                    params.insert(name.to_string(), param_lines.len());
+                    param_lines.push(ParamLine { name: name.to_string(), min, max, default });
+                },
+                ["param", ..] => return Err(error("a param is written as: param <name> <min> <max> <default>".to_string())),
+                ["out", sources @ ..] => {
+                    if outputs.is_some() {
+                        return Err(error("there is already an out line".to_string()));
+                    }
+                    if sources.is_empty() || sources.len() > 2 {
+                        return Err(error("out takes one source for mono or two for left and right".to_string()));
+                    }
+                    outputs = Some(sources.iter().map(|word| source(word)).collect::<Result<Vec<_>, _>>()?);
+                },
+                [name, "=", kind, inputs @ ..] => {
+                    new_name(name)?;
+                    let kind = Kind::parse(kind).ok_or_else(|| error(format!("there is no node kind \"{kind}\"")))?;
+                    let expected = kind.inputs();
+                    if inputs.len() != expected.len() {
+                        return Err(error(format!("{} takes {} inputs ({}), not {}", words[2], expected.len(), expected.join(", "), inputs.len())));
+                    }
+                    let inputs = inputs.iter().map(|word| source(word)).collect::<Result<Vec<_>, _>>()?;
+                    names.insert(name.to_string(), nodes.len());
+                    nodes.push(Node { name: name.to_string(), kind, inputs, envelope: None });
+                },
+                _ => return Err(error("expected param, out or <name> = <kind> <inputs>".to_string())),
+            }
+        }
+        let outputs = outputs.ok_or("the patch has no out line")?;
+        let params = register(&param_lines, &mut nodes, registry, prefix);
+        Ok(Self { nodes, outputs, params })
+    }
+
+    // 1 for mono, 2 for stereo
+    pub fn outputs(&self) -> usize {
+        self.outputs.len()
+    }
+
+    // The graph of one voice.
+    // - Input 0: frequency
+    // - Input 1: gate
+    // - Input 2: velocity
+    // - Output(s): the out line of the patch
+    pub fn build(&self) -> Net64 {
+        let mut net = Net64::new(VOICE_INPUTS.len(), self.outputs.len());
+        let mut ids = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let id = net.push(node.unit());
+            for (port, source) in node.inputs.iter().enumerate() {
+                match source {
+                    Source::Voice(input) => net.connect_input(*input, id, port),
+                    source => {
+                        let source = self.push_source(&mut net, &ids, source);
+                        net.connect(source, 0, id, port);
+                    },
+                }
+            }
+            ids.push(id);
+        }
+        for (output, source) in self.outputs.iter().enumerate() {
+            match source {
+                Source::Voice(input) => net.pass_through(*input, output),
+                source => {
+                    let source = self.push_source(&mut net, &ids, source);
+                    net.connect_output(source, 0, output);
+                },
+            }
+        }
+        net
+    }
+
+    // The node that a number, a param or a node input comes from
+    fn push_source(&self, net: &mut Net64, ids: &[NodeId], source: &Source) -> NodeId {
+        match source {
+            Source::Number(value) => net.push(Box::new(dc(*value))),
+            Source::Param(index) => net.push(Box::new(var(self.params[*index].shared()))),
+            Source::Node(index) => ids[*index],
+            Source::Voice(_) => unreachable!("voice inputs are connected to the inputs of the net"),
+        }
+    }
+}
+
+// Makes the params under the prefix match a patch that was just parsed. A param whose range or default changed
+// in the text is replaced and starts from its new default, and params the text doesn't have anymore are taken
+// out, so they don't stay in the presets. The graph that is still playing keeps the params it has.
+fn register(lines: &[ParamLine], nodes: &mut [Node], registry: &ParamRegistry, prefix: &str) -> Vec<Param> {
+    let params = lines.iter()
+        .map(|line| {
+            let name = format!("{prefix}.{}", line.name);
+            if let Some(old) = registry.get(&name) && (old.range() != (line.min, line.max) || old.default_value() != line.default) {
+                registry.retain(|param| param.name() != name);
+                println!("{name} is now {} to {}, back to its default {}", line.min, line.max, line.default);
+            }
+            registry.add(&name, line.min, line.max, line.default)
+        })
+        .collect::<Vec<_>>();
+    for node in nodes.iter_mut().filter(|node| matches!(node.kind, Kind::Adsr)) {
+        node.envelope = Some(Box::new(AdsrParams::new(registry, &format!("{prefix}.{}", node.name), 0.01, 0.2, 0.7, 0.3)));
+    }
+
+    let envelopes: Vec<String> = nodes.iter().filter(|node| node.envelope.is_some()).map(|node| format!("{prefix}.{}.", node.name)).collect();
+    let prefix = format!("{prefix}.");
+    registry.retain(|param| {
+        !param.name().starts_with(&prefix)
+            || params.iter().any(|kept| kept.name() == param.name())
+            || envelopes.iter().any(|envelope| param.name().starts_with(envelope))
+    });
+    params
+}
+
+// All voices playing the patch, summed in stereo. Mono patches are the same on both sides.
+pub fn patch_synth(patch: &Patch, voices: &[Voice]) -> Net64 {
+    voices.iter()
+        .map(|voice| {
+            let net = Net64::wrap(Box::new(var(&voice.freq) | var(&voice.gate) | var(&voice.velocity))) >> patch.build();
+            if patch.outputs() == 1 { net >> Net64::wrap(Box::new(split::<U2>())) } else { net }
+        })
+        .reduce(|a, b| a + b)
+        .unwrap()
+}
@@ -0,0 +1,333 @@
+use std::f64::consts::TAU;
+use fundsp::hacker::*;
+
+use super::effects::DelayLine;
+use super::noise::Noise;
+use super::params::{Param, ParamRegistry};
+
+// Lowest note the string can play, this sets the length of its delay line
+const LOWEST: f64 = 20.0;
+// How quickly a string or resonator dies out after note off (seconds to -60 dB)
+const DAMPED: f64 = 0.15;
+
+// Feedback per sample for a decay of 60 dB in the given time
+fn decay_gain(time: f64, sample_rate: f64) -> f64 {
+    (-6.91 / (time.max(0.001) * sample_rate)).exp()
+}
+
+#[derive(Clone)]
+pub struct StringParams {
+    // 0.0 rings for 10 seconds, 1.0 is dead after a tenth of a second
+    pub damping: Param,
+    // Cutoff of the loss filter in the loop, dark strings lose their overtones quickly
+    pub brightness: Param,
+    // Where the string is plucked as a part of its length, 0.5 is the middle and leaves out the even harmonics
+    pub pick: Param,
+}
+
+impl StringParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            damping: registry.add(&format!("{prefix}.damping"), 0.0, 1.0, 0.3),
+            brightness: registry.add(&format!("{prefix}.brightness"), 0.0, 1.0, 0.6),
+            pick: registry.add(&format!("{prefix}.pick"), 0.02, 0.5, 0.2),
+        }
+    }
+}
+
+// Karplus-Strong plucked string: a burst of noise circulates in a delay line one period long,
+// and a lowpass in the loop takes the high harmonics out faster than the low ones.
+// - Input 0: frequency in Hz.
+// - Input 1: gate. Any new positive value plucks, zero damps the string.
+// - Input 2: velocity.
+// - Output 0: the string.
+#[derive(Clone)]
+pub struct PluckedString {
+    params: StringParams,
+    delay: DelayLine,
+    // The pluck, played into the loop over the first period
+    excitation: Vec<f64>,
+    excitation_index: usize,
+    lowpass: f64,
+    noise: Noise,
+    last_gate: f64,
+    sample_rate: f64,
+}
+
+impl PluckedString {
+    pub fn new(params: &StringParams) -> Self {
+        let mut string = Self {
+            params: params.clone(),
+            delay: DelayLine::default(),
+            excitation: Vec::new(),
+            excitation_index: 0,
+            lowpass: 0.0,
+            noise: Noise::new(0x5eed_0001),
+            last_gate: 0.0,
+            sample_rate: 0.0,
+        };
+        string.set_sample_rate(DEFAULT_SR);
+        string
+    }
+
+    fn pluck(&mut self, period: usize, velocity: f64) {
+        // Brighter plucks have more highs in the noise burst too
+        let coefficient = 1.0 - self.params.brightness.value() * 0.9;
+        let mut state = 0.0;
+        for i in 0..period {
+            state += (self.noise.next() - state) * (1.0 - coefficient);
+            self.excitation[i] = state * velocity;
+        }
+        // Plucking at a point cancels the harmonics that have a node there, a comb filter on the burst
+        let offset = std::cmp::max((self.params.pick.value() * period as f64) as usize, 1);
+        for i in (offset..period).rev() {
+            self.excitation[i] -= self.excitation[i - offset];
+        }
+        self.excitation[period..].iter_mut().for_each(|x| *x = 0.0);
+        self.excitation_index = 0;
+    }
+}
+
+impl AudioNode for PluckedString {
+    const ID: u64 = 0x4f58_0009;
+    type Sample = f64;
+    type Inputs = U3;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.delay.clear();
+        self.excitation_index = self.excitation.len();
+        self.lowpass = 0.0;
+        self.last_gate = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            let length = (sample_rate / LOWEST) as usize + 2;
+            self.delay = DelayLine::new(length);
+            self.excitation = vec![0.0; length];
+            self.reset();
+        }
+    }
+
+    fn tick(&mut self, input: &Frame<f64, U3>) -> Frame<f64, U1> {
+        let (freq, gate, velocity) = (input[0].max(LOWEST), input[1], input[2]);
+        let period = self.sample_rate / freq;
+        if gate > 0.0 && gate != self.last_gate {
+            let whole = std::cmp::min(period as usize, self.excitation.len());
+            self.pluck(whole, velocity);
+        }
+        self.last_gate = gate;
+
+        // One pole lowpass as the loss filter, its delay comes off the loop so the pitch stays right
+        let coefficient = 0.9 * (1.0 - self.params.brightness.value());
+        let time = if gate > 0.0 { 10.0 * 0.01_f64.powf(self.params.damping.value()) } else { DAMPED };
+        // Every sample goes through here once per trip around the loop, so the loss is per period
+        // and the decay time doesn't depend on the pitch
+        let loss = decay_gain(time, self.sample_rate).powf(period);
+        let loop_delay = (period - 1.0 - coefficient / (1.0 - coefficient)).max(1.0);
+
+        let output = self.delay.read(loop_delay);
+        self.lowpass += (output - self.lowpass) * (1.0 - coefficient);
+        let feedback = self.lowpass * loss;
+        let excitation = self.excitation.get(self.excitation_index).copied().unwrap_or(0.0);
+        self.excitation_index += 1;
+        self.delay.write(feedback + excitation);
+        [output].into()
+    }
+}
+
+pub fn plucked_string(params: &StringParams) -> An<PluckedString> {
+    An(PluckedString::new(params))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ModalModel {
+    #[default]
+    Bell,
+    Bar,
+    Membrane,
+}
+
+impl ModalModel {
+    pub const ALL: [ModalModel; 3] = [ModalModel::Bell, ModalModel::Bar, ModalModel::Membrane];
+
+    pub fn from_value(value: f64) -> Self {
+        let index = value.round().clamp(0.0, (Self::ALL.len() - 1) as f64);
+        Self::ALL[index as usize]
+    }
+
+    // Frequencies of the modes as multiples of the note
+    fn ratios(&self) -> [f64; MODES] {
+        match self {
+            // Hum, prime, tierce, quint, nominal and the ones above of a church bell
+            ModalModel::Bell => [0.5, 1.0, 1.183, 1.506, 2.0, 2.514, 2.662, 3.011],
+            // A bar that is free at both ends, like a glockenspiel
+            ModalModel::Bar => [1.0, 2.756, 5.404, 8.933, 13.345, 18.64, 26.0, 34.6],
+            // A round drum skin
+            ModalModel::Membrane => [1.0, 1.593, 2.136, 2.296, 2.653, 2.918, 3.156, 3.501],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Excitation {
+    #[default]
+    Noise,
+    Impulse,
+    // The audio input of the node rings the modes while the note is held
+    Input,
+}
+
+impl Excitation {
+    pub const ALL: [Excitation; 3] = [Excitation::Noise, Excitation::Impulse, Excitation::Input];
+
+    pub fn from_value(value: f64) -> Self {
+        let index = value.round().clamp(0.0, (Self::ALL.len() - 1) as f64);
+        Self::ALL[index as usize]
+    }
+}
+
+const MODES: usize = 8;
+// Length of the noise burst in seconds
+const BURST: f64 = 0.01;
+
+#[derive(Clone)]
+pub struct ModalParams {
+    pub model: Param,
+    pub excitation: Param,
+    // Decay of the lowest mode in seconds
+    pub decay: Param,
+    // Level and decay of the high modes compared to the low ones
+    pub brightness: Param,
+    pub release: Param,
+}
+
+impl ModalParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            model: registry.add(&format!("{prefix}.model"), 0.0, (ModalModel::ALL.len() - 1) as f64, 0.0),
+            excitation: registry.add(&format!("{prefix}.excitation"), 0.0, (Excitation::ALL.len() - 1) as f64, 0.0),
+            decay: registry.add(&format!("{prefix}.decay"), 0.05, 10.0, 3.0),
+            brightness: registry.add(&format!("{prefix}.brightness"), 0.0, 1.0, 0.5),
+            release: registry.add(&format!("{prefix}.release"), 0.05, 10.0, 2.0),
+        }
+    }
+}
+
+// Two pole resonator for one mode
+#[derive(Clone, Copy, Default)]
+struct Mode {
+    y1: f64,
+    y2: f64,
+}
+
+// A bank of resonators tuned to the modes of a bell, a bar or a membrane
+// - Input 0: frequency in Hz.
+// - Input 1: gate. Any new positive value strikes, zero damps the modes after the release time.
+// - Input 2: velocity.
+// - Input 3: external audio for the input excitation.
+// - Output 0: the resonators.
+#[derive(Clone)]
+pub struct ModalVoice {
+    params: ModalParams,
+    modes: [Mode; MODES],
+    noise: Noise,
+    // Samples left of the noise burst, and its level
+    burst: usize,
+    strike: f64,
+    impulse: f64,
+    last_gate: f64,
+    sample_rate: f64,
+}
+
+impl ModalVoice {
+    pub fn new(params: &ModalParams) -> Self {
+        Self {
+            params: params.clone(),
+            modes: [Mode::default(); MODES],
+            noise: Noise::new(0x5eed_0002),
+            burst: 0,
+            strike: 0.0,
+            impulse: 0.0,
+            last_gate: 0.0,
+            sample_rate: DEFAULT_SR,
+        }
+    }
+}
+
+impl AudioNode for ModalVoice {
+    const ID: u64 = 0x4f58_000a;
+    type Sample = f64;
+    type Inputs = U4;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.modes = [Mode::default(); MODES];
+        self.burst = 0;
+        self.impulse = 0.0;
+        self.last_gate = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, input: &Frame<f64, U4>) -> Frame<f64, U1> {
+        let (freq, gate, velocity, audio) = (input[0], input[1], input[2], input[3]);
+        let excitation = Excitation::from_value(self.params.excitation.value());
+        if gate > 0.0 && gate != self.last_gate {
+            match excitation {
+                Excitation::Noise => {
+                    self.burst = (BURST * self.sample_rate) as usize;
+                    self.strike = velocity;
+                },
+                Excitation::Impulse => self.impulse = velocity,
+                Excitation::Input => (),
+            }
+        }
+        self.last_gate = gate;
+
+        let x = match excitation {
+            Excitation::Noise if self.burst > 0 => {
+                self.burst -= 1;
+                // Spread over the burst, so it adds up to about one impulse
+                self.noise.next() * self.strike * 0.1
+            },
+            Excitation::Impulse => std::mem::take(&mut self.impulse),
+            Excitation::Input if gate > 0.0 => audio,
+            _ => 0.0,
+        };
+
+        let brightness = self.params.brightness.value();
+        let decay = if gate > 0.0 { self.params.decay.value() } else { self.params.release.value().min(self.params.decay.value()).max(DAMPED) };
+        let ratios = ModalModel::from_value(self.params.model.value()).ratios();
+        let mut output = 0.0;
+        for (i, (mode, ratio)) in self.modes.iter_mut().zip(ratios).enumerate() {
+            let mode_freq = freq * ratio;
+            // Modes above Nyquist would fold back down, leave them out
+            if mode_freq >= self.sample_rate * 0.45 {
+                continue;
+            }
+            // Higher modes are quieter and die out sooner, less so when it's bright
+            let dull = 1.0 - brightness;
+            let level = 1.0 / (1.0 + i as f64 * dull * 2.0);
+            let r = decay_gain(decay / (1.0 + i as f64 * dull), self.sample_rate);
+            let w = TAU * mode_freq / self.sample_rate;
+            // Scaled so an impulse rings at its own height, and a steady sine at the mode about at its own
+            let drive = if excitation == Excitation::Input { 2.0 * (1.0 - r) } else { 1.0 };
+            let y = 2.0 * r * w.cos() * mode.y1 - r * r * mode.y2 + x * w.sin() * drive * level;
+            mode.y2 = mode.y1;
+            mode.y1 = y;
+            output += y;
+        }
+        [output * 0.5].into()
+    }
+}
+
+pub fn modal_voice(params: &ModalParams) -> An<ModalVoice> {
+    An(ModalVoice::new(params))
+}
@@ -0,0 +1,137 @@
+use super::voice::NoteEvent;
+
+// Lowest and highest pitch we look for, that's a low E on a bass up to a whistle
+const LOWEST: f64 = 40.0;
+const HIGHEST: f64 = 2000.0;
+// Quieter signals are not tracked at all, noise has no pitch
+const SILENCE: f32 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pitch {
+    pub frequency: f64,
+    // Nearest note and how far the frequency is from it, -50.0 to 50.0
+    pub note: u8,
+    pub cents: f64,
+    // 0.0 is a guess, 1.0 is a perfectly periodic signal
+    pub confidence: f64,
+}
+
+impl Pitch {
+    fn from_frequency(frequency: f64, confidence: f64) -> Self {
+        let exact = 69.0 + 12.0 * (frequency / 440.0).log2();
+        let note = exact.round().clamp(0.0, 127.0);
+        Self { frequency, note: note as u8, cents: (exact - note) * 100.0, confidence }
+    }
+}
+
+// The YIN pitch detector (de Cheveigné and Kawahara, 2002)
+pub struct Yin {
+    sample_rate: f64,
+    // The first dip of the normalized difference below this is taken as the period
+    threshold: f32,
+    difference: Vec<f32>,
+}
+
+impl Yin {
+    pub fn new(sample_rate: f64) -> Self {
+        Self { sample_rate, threshold: 0.15, difference: Vec::new() }
+    }
+
+    // Samples detect() needs to see two periods of the lowest pitch, 2400 at 48 kHz
+    pub fn window_length(sample_rate: f64) -> usize {
+        (2.0 * sample_rate / LOWEST).ceil() as usize
+    }
+
+    // Shorter windows than window_length() miss the lowest notes
+    pub fn detect(&mut self, samples: &[f32]) -> Option<Pitch> {
+        let length = samples.len() / 2;
+        let max_lag = ((self.sample_rate / LOWEST) as usize).min(length);
+        let min_lag = ((self.sample_rate / HIGHEST) as usize).max(2);
+        if max_lag <= min_lag + 2 {
+            return None;
+        }
+        let rms = (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt();
+        if rms < SILENCE {
+            return None;
+        }
+
+        // Squared difference between the signal and itself shifted by each lag
+        self.difference.clear();
+        self.difference.resize(max_lag, 0.0);
+        for lag in 1..max_lag {
+            self.difference[lag] = (0..length).map(|i| {
+                let delta = samples[i] - samples[i + lag];
+                delta * delta
+            }).sum();
+        }
+
+        // Cumulative mean normalized difference, this is what stops YIN from picking octaves too low
+        self.difference[0] = 1.0;
+        let mut sum = 0.0;
+        for lag in 1..max_lag {
+            sum += self.difference[lag];
+            self.difference[lag] = if sum > 0.0 { self.difference[lag] * lag as f32 / sum } else { 1.0 };
+        }
+
+        // The first dip under the threshold, followed down to its bottom
+        let mut lag = (min_lag..max_lag).find(|lag| self.difference[*lag] < self.threshold)?;
+        while lag + 1 < max_lag && self.difference[lag + 1] < self.difference[lag] {
+            lag += 1;
+        }
+
+        // Parabola through the bottom and its neighbours for a lag between samples
+        let (a, b, c) = (self.difference[lag - 1], self.difference[lag], self.difference[(lag + 1).min(max_lag - 1)]);
+        let denominator = a - 2.0 * b + c;
+        let offset = if denominator.abs() > 1.0e-9 { (0.5 * (a - c) / denominator).clamp(-0.5, 0.5) } else { 0.0 };
+        let period = lag as f64 + offset as f64;
+        Some(Pitch::from_frequency(self.sample_rate / period, (1.0 - b as f64).clamp(0.0, 1.0)))
+    }
+}
+
+// Turns a stream of detected pitches into note on and note off events, like an audio to MIDI converter.
+// A note has to be detected a few times in a row before it starts, so the detector wobbling at the
+// start of a note doesn't produce a burst of short notes.
+pub struct NoteFollower {
+    playing: Option<u8>,
+    candidate: Option<u8>,
+    count: usize,
+    min_confidence: f64,
+}
+
+impl NoteFollower {
+    const STABLE: usize = 3;
+    const VELOCITY: f64 = 0.8;
+
+    pub fn new(min_confidence: f64) -> Self {
+        Self { playing: None, candidate: None, count: 0, min_confidence }
+    }
+
+    pub fn update(&mut self, pitch: Option<Pitch>) -> Vec<NoteEvent> {
+        let note = pitch.filter(|pitch| pitch.confidence >= self.min_confidence).map(|pitch| pitch.note);
+        if note == self.candidate {
+            self.count += 1;
+        } else {
+            self.candidate = note;
+            self.count = 1;
+        }
+
+        let mut events = Vec::new();
+        if self.count == Self::STABLE && self.candidate != self.playing {
+            if let Some(playing) = self.playing.take() {
+                events.push(NoteEvent::Off(playing));
+            }
+            if let Some(note) = self.candidate {
+                events.push(NoteEvent::On(note, Self::VELOCITY));
+                self.playing = Some(note);
+            }
+        }
+        events
+    }
+
+    // Ends the note that is playing, for when the mode is switched off
+    pub fn stop(&mut self) -> Option<NoteEvent> {
+        self.candidate = None;
+        self.count = 0;
+        self.playing.take().map(NoteEvent::Off)
+    }
+}
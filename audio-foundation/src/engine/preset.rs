@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use super::effects::EffectKind;
+use super::instruments::LeadOscillator;
+use super::modulation::ModMatrixSettings;
+use super::tuning::TuningSettings;
+
+// A snapshot of the synth that is stored as a RON file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preset {
+    pub name: String,
+    pub params: BTreeMap<String, f64>,
+    pub modulation: ModMatrixSettings,
+    // Order of the master effects, their settings are in params
+    pub effects: Vec<EffectKind>,
+    // Scale and keyboard map files, the reference pitch is in params
+    pub tuning: TuningSettings,
+    // What the lead plays, switching it rebuilds the lead track
+    pub lead: LeadOscillator,
+}
+
+impl Preset {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_text()?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::from_text(&fs::read_to_string(path)?)
+    }
+
+    // The contents of the file, the plugin stores this as its state
+    pub fn to_text(&self) -> Result<String, Box<dyn Error>> {
+        Ok(ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(ron::from_str(text)?)
+    }
+}
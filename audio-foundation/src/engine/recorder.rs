@@ -0,0 +1,176 @@
+use std::{fs, path::{Path, PathBuf}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc, Arc};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+enum Command {
+    // The file for a take, takes are numbered from 1
+    Start(PathBuf, usize),
+    Stop,
+}
+
+// Frames carry the number of their take, so when a take starts right after a stop
+// its first frames don't end up at the end of the file before
+type TakeFrame = (usize, [f32; 2]);
+
+// The audio thread end: copies the master output into the ring buffer while recording is on
+pub struct RecorderTap {
+    producer: Producer<TakeFrame>,
+    recording: Arc<AtomicBool>,
+    take: Arc<AtomicUsize>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl RecorderTap {
+    pub fn push(&mut self, left: f32, right: f32) {
+        if self.recording.load(Ordering::Acquire) && self.producer.push((self.take.load(Ordering::Relaxed), [left, right])).is_err() {
+            // The writer thread fell behind, the file will have a gap
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Records the master output to 32 bit float WAV files. The files are written on a thread of their own,
+// so the audio callback never waits for the disk.
+pub struct Recorder {
+    folder: PathBuf,
+    recording: Arc<AtomicBool>,
+    take: Arc<AtomicUsize>,
+    dropped: Arc<AtomicUsize>,
+    commands: mpsc::Sender<Command>,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(folder: P, sample_rate: f64) -> (Self, RecorderTap) {
+        // Two seconds of room, in case the disk is slow for a moment
+        let (producer, consumer) = RingBuffer::new((sample_rate * 2.0) as usize);
+        let recording = Arc::new(AtomicBool::new(false));
+        let take = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (commands, receiver) = mpsc::channel();
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        thread::spawn(move || write_files(consumer, receiver, spec));
+
+        let tap = RecorderTap { producer, recording: Arc::clone(&recording), take: Arc::clone(&take), dropped: Arc::clone(&dropped) };
+        (Self { folder: folder.as_ref().to_path_buf(), recording, take, dropped, commands }, tap)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    // Starts a new file named after the current time, like "recordings/2024-05-01_20-15-00.wav"
+    pub fn start(&self) {
+        let path = self.folder.join(format!("{}.wav", timestamp()));
+        self.dropped.store(0, Ordering::Relaxed);
+        let take = self.take.fetch_add(1, Ordering::Relaxed) + 1;
+        // The writer gets the file name before any frames arrive, and the tap sees the new take once it sees recording is on
+        self.commands.send(Command::Start(path, take)).unwrap();
+        self.recording.store(true, Ordering::Release);
+    }
+
+    // Returns the number of frames that were lost because the writer couldn't keep up
+    pub fn stop(&self) -> usize {
+        self.recording.store(false, Ordering::Relaxed);
+        self.commands.send(Command::Stop).unwrap();
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// The writer thread: empties the ring buffer into the open file every few milliseconds
+fn write_files(mut consumer: Consumer<TakeFrame>, commands: mpsc::Receiver<Command>, spec: hound::WavSpec) {
+    let mut writer: Option<hound::WavWriter<_>> = None;
+    // The take of the last start, the file is open while it records
+    let mut take = 0;
+    loop {
+        match commands.try_recv() {
+            Ok(Command::Start(path, started)) => {
+                take = started;
+                let path = unused(path);
+                let file = path.parent().map_or(Ok(()), fs::create_dir_all)
+                    .map_err(hound::Error::from)
+                    .and_then(|()| hound::WavWriter::create(&path, spec));
+                match file {
+                    Ok(file) => {
+                        println!("Recording to {}", path.display());
+                        writer = Some(file);
+                    },
+                    Err(err) => eprintln!("Could not create {}: {err}", path.display()),
+                }
+            },
+            Ok(Command::Stop) => {
+                // Give the audio thread time to see that recording is off, then write what is left of the take
+                thread::sleep(Duration::from_millis(50));
+                if let Some(mut file) = writer.take() {
+                    drain(&mut consumer, &mut file, take);
+                    if let Err(err) = file.finalize() {
+                        eprintln!("Could not finish the recording: {err}");
+                    }
+                }
+            },
+            Err(mpsc::TryRecvError::Disconnected) => break,
+            Err(mpsc::TryRecvError::Empty) => (),
+        }
+        match &mut writer {
+            Some(file) => drain(&mut consumer, file, take),
+            // Frames without a file to go to are thrown away, unless they are the start of a take we didn't open yet
+            None => skip(&mut consumer, take),
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Writes the frames of one take and throws away what is left of earlier ones. Frames of later takes stay in the ring.
+fn drain<W: std::io::Write + std::io::Seek>(consumer: &mut Consumer<TakeFrame>, file: &mut hound::WavWriter<W>, take: usize) {
+    while let Ok(&(frame_take, [left, right])) = consumer.peek() {
+        if frame_take > take {
+            return;
+        }
+        let _ = consumer.pop();
+        if frame_take == take && file.write_sample(left).and_then(|()| file.write_sample(right)).is_err() {
+            eprintln!("Could not write to the recording");
+            return;
+        }
+    }
+}
+
+// Throws away the frames of this take and the ones before it
+fn skip(consumer: &mut Consumer<TakeFrame>, take: usize) {
+    while consumer.peek().is_ok_and(|(frame_take, _)| *frame_take <= take) {
+        let _ = consumer.pop();
+    }
+}
+
+// A take that starts in the same second as the last one gets "_2" and so on, instead of overwriting it
+fn unused(path: PathBuf) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let mut candidate = path.clone();
+    for number in 2.. {
+        if !candidate.exists() {
+            break;
+        }
+        candidate = path.with_file_name(format!("{stem}_{number}.wav"));
+    }
+    candidate
+}
+
+// The current UTC time as "YYYY-MM-DD_HH-MM-SS", so recordings sort by date
+fn timestamp() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // Civil date from days since 1970 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{year:04}-{month:02}-{day:02}_{:02}-{:02}-{:02}", time / 3600, time % 3600 / 60, time % 60)
+}
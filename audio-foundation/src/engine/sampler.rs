@@ -0,0 +1,335 @@
+use std::{error::Error, fs, path::Path, sync::Arc};
+use fundsp::hacker::*;
+
+use super::envelope::{Adsr, AdsrParams};
+use super::params::{Param, ParamRegistry};
+use super::tuning::Tuning;
+use super::voice::Voice;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LoopMode {
+    #[default]
+    Off,
+    Forward,
+    PingPong,
+}
+
+impl LoopMode {
+    pub const ALL: [LoopMode; 3] = [LoopMode::Off, LoopMode::Forward, LoopMode::PingPong];
+
+    pub fn from_value(value: f64) -> Self {
+        let index = value.round().clamp(0.0, (Self::ALL.len() - 1) as f64);
+        Self::ALL[index as usize]
+    }
+}
+
+// A WAV file in memory as stereo frames, mono files are copied to both sides
+pub struct Sample {
+    pub name: String,
+    frames: Vec<[f32; 2]>,
+    pub sample_rate: f64,
+}
+
+impl Sample {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>().map(|s| s.map(|s| s as f32 * scale)).collect::<Result<_, _>>()?
+            },
+        };
+        let channels = spec.channels as usize;
+        let frames: Vec<_> = samples.chunks_exact(channels)
+            .map(|frame| [frame[0], frame[if channels > 1 { 1 } else { 0 }]])
+            .collect();
+        if frames.is_empty() {
+            return Err("The file has no audio in it.".into());
+        }
+        Ok(Self {
+            name: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            frames,
+            sample_rate: spec.sample_rate as f64,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Cubic (Hermite) interpolation between the frames around a fractional position
+    pub fn read(&self, position: f64) -> (f64, f64) {
+        let index = position.floor() as isize;
+        let t = position - index as f64;
+        let frame = |i: isize| self.frames[i.clamp(0, self.frames.len() as isize - 1) as usize];
+        let mut output = [0.0; 2];
+        for (channel, output) in output.iter_mut().enumerate() {
+            let [y0, y1, y2, y3] = [-1, 0, 1, 2].map(|offset| frame(index + offset)[channel] as f64);
+            let c1 = 0.5 * (y2 - y0);
+            let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+            let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+            *output = ((c3 * t + c2) * t + c1) * t + y1;
+        }
+        (output[0], output[1])
+    }
+}
+
+// One sample on a range of keys, played at its original pitch on the root key
+pub struct Zone {
+    pub sample: Sample,
+    pub root: u8,
+    pub low: u8,
+    pub high: u8,
+    // Pitch of the root in the tuning, relative to the reference note. Shared so a new tuning can be set while playing.
+    root_ratio: Shared<f64>,
+}
+
+impl Zone {
+    fn new(sample: Sample, root: u8, low: u8, high: u8) -> Self {
+        // Equal temperament until a tuning is set
+        let root_ratio = shared(exp2((root as f64 - 69.0) / 12.0));
+        Self { sample, root, low, high, root_ratio }
+    }
+}
+
+// Which sample plays on which note
+#[derive(Default)]
+pub struct Keymap {
+    zones: Vec<Zone>,
+}
+
+impl Keymap {
+    // Loads every .wav file in a folder.
+    // A file that starts with a note number ("48 piano.wav") is a multisample: it is pitched across the keys
+    // from its root up to the next numbered file. The other files become pads, one note each, from first_pad up
+    // in the order of their names.
+    pub fn load_folder<P: AsRef<Path>>(folder: P, first_pad: u8) -> Result<Self, Box<dyn Error>> {
+        let mut paths: Vec<_> = fs::read_dir(folder)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wav")))
+            .collect();
+        paths.sort();
+
+        let mut pitched = Vec::new();
+        let mut pads = Vec::new();
+        for path in paths {
+            let sample = Sample::load(&path).map_err(|err| format!("{}: {err}", path.display()))?;
+            let number: String = sample.name.chars().take_while(|c| c.is_ascii_digit()).collect();
+            match number.parse::<u8>() {
+                Ok(root) if root < 128 => pitched.push((root, sample)),
+                _ => pads.push(sample),
+            }
+        }
+        pitched.sort_by_key(|(root, _)| *root);
+
+        let mut zones = Vec::new();
+        let roots: Vec<u8> = pitched.iter().map(|(root, _)| *root).collect();
+        for (i, (root, sample)) in pitched.into_iter().enumerate() {
+            // The lowest zone also covers everything below it
+            let low = if i == 0 { 0 } else { root };
+            let high = roots.get(i + 1).map_or(127, |next| next - 1);
+            zones.push(Zone::new(sample, root, low, high));
+        }
+        for (i, sample) in pads.into_iter().enumerate() {
+            let Some(note) = first_pad.checked_add(i as u8).filter(|note| *note < 128) else { break };
+            // Pads are placed in front so they win over a multisample on the same note
+            zones.insert(i, Zone::new(sample, note, note, note));
+        }
+        Ok(Self { zones })
+    }
+
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    // Roots the tuning leaves out keep the pitch they had
+    pub fn set_tuning(&self, tuning: &Tuning) {
+        for zone in &self.zones {
+            if let Some(ratio) = tuning.ratio(zone.root) {
+                zone.root_ratio.set_value(ratio);
+            }
+        }
+    }
+
+    fn find(&self, note: u8) -> Option<usize> {
+        self.zones.iter().position(|zone| (zone.low..=zone.high).contains(&note))
+    }
+}
+
+#[derive(Clone)]
+pub struct SamplerParams {
+    // Playback region as a fraction of the sample length
+    pub start: Param,
+    pub end: Param,
+    pub loop_mode: Param,
+    // 0.0 plays to the end of the sample whatever happens, 1.0 plays while the key is held
+    pub gated: Param,
+    pub reverse: Param,
+    // Transposition in semitones
+    pub tune: Param,
+    pub amp_env: AdsrParams,
+}
+
+impl SamplerParams {
+    pub fn new(registry: &ParamRegistry, prefix: &str) -> Self {
+        Self {
+            start: registry.add(&format!("{prefix}.start"), 0.0, 1.0, 0.0),
+            end: registry.add(&format!("{prefix}.end"), 0.0, 1.0, 1.0),
+            loop_mode: registry.add(&format!("{prefix}.loop"), 0.0, (LoopMode::ALL.len() - 1) as f64, 0.0),
+            gated: registry.add(&format!("{prefix}.gated"), 0.0, 1.0, 1.0),
+            reverse: registry.add(&format!("{prefix}.reverse"), 0.0, 1.0, 0.0),
+            tune: registry.add(&format!("{prefix}.tune"), -24.0, 24.0, 0.0),
+            amp_env: AdsrParams::new(registry, &format!("{prefix}.amp_env"), 0.001, 0.1, 1.0, 0.05),
+        }
+    }
+}
+
+// One sampler voice, played by a voice of the VoiceAllocator. The zone is picked from the note of the
+// voice and the sample is resampled by the ratio between its frequency and the tuned frequency of the root.
+// - Output 0: left.
+// - Output 1: right.
+#[derive(Clone)]
+pub struct SamplerVoice {
+    voice: Voice,
+    keymap: Arc<Keymap>,
+    params: SamplerParams,
+    // Reference frequency of the tuning
+    reference: Param,
+    adsr: Adsr,
+    last_gate: f64,
+    velocity: f64,
+    zone: Option<usize>,
+    // Frequency the root of the zone had when the note started, like the frequency of the voice
+    root_freq: f64,
+    // Position in frames of the sample and the direction we are moving in
+    position: f64,
+    direction: f64,
+    gated: bool,
+    sample_rate: f64,
+}
+
+impl SamplerVoice {
+    pub fn new(voice: &Voice, keymap: &Arc<Keymap>, params: &SamplerParams, reference: &Param) -> Self {
+        Self {
+            voice: voice.clone(),
+            keymap: Arc::clone(keymap),
+            params: params.clone(),
+            reference: reference.clone(),
+            adsr: Adsr::default(),
+            last_gate: 0.0,
+            velocity: 0.0,
+            zone: None,
+            root_freq: 440.0,
+            position: 0.0,
+            direction: 1.0,
+            gated: true,
+            sample_rate: DEFAULT_SR,
+        }
+    }
+
+    // The playback region in frames, start is always before end
+    fn region(&self, sample: &Sample) -> (f64, f64) {
+        let last = (sample.len() - 1) as f64;
+        let start = self.params.start.value().clamp(0.0, 1.0) * last;
+        let end = self.params.end.value().clamp(0.0, 1.0) * last;
+        (start.min(end), start.max(end))
+    }
+
+    fn trigger(&mut self) {
+        let note = self.voice.note.value().round().clamp(0.0, 127.0) as u8;
+        self.zone = self.keymap.find(note);
+        let Some(zone) = self.zone else { return };
+
+        let zone = &self.keymap.zones[zone];
+        self.root_freq = zone.root_ratio.value() * self.reference.value();
+        let (start, end) = self.region(&zone.sample);
+        let reverse = self.params.reverse.value() >= 0.5;
+        self.position = if reverse { end } else { start };
+        self.direction = if reverse { -1.0 } else { 1.0 };
+        self.gated = self.params.gated.value() >= 0.5;
+        self.velocity = self.voice.velocity.value();
+        self.adsr.retrigger();
+    }
+
+    // The envelope starts over too, so the attack of the next note doesn't begin at the level this one stopped at
+    fn stop(&mut self) {
+        self.zone = None;
+        self.adsr = Adsr::default();
+    }
+}
+
+impl AudioNode for SamplerVoice {
+    const ID: u64 = 0x4f58_0004;
+    type Sample = f64;
+    type Inputs = U0;
+    type Outputs = U2;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.stop();
+        self.last_gate = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, _input: &Frame<f64, U0>) -> Frame<f64, U2> {
+        let gate = self.voice.gate.value();
+        if gate > 0.0 && gate != self.last_gate {
+            self.trigger();
+        } else if gate <= 0.0 && self.gated {
+            // One shots ignore the note off
+            self.adsr.set_gate(false);
+        }
+        self.last_gate = gate;
+
+        // Everything this needs from the zone is read first, so the voice can stop itself further down
+        let Some(zone) = self.zone else {
+            return [0.0, 0.0].into();
+        };
+        let sample = &self.keymap.zones[zone].sample;
+        let (start, end) = self.region(sample);
+        let (left, right) = sample.read(self.position);
+        let sample_rate = sample.sample_rate;
+
+        self.params.amp_env.update(&mut self.adsr);
+        let level = self.adsr.next(1.0 / self.sample_rate) * self.params.amp_env.velocity_scale(self.velocity);
+        if self.adsr.is_idle() {
+            self.stop();
+        }
+
+        // Resample by the pitch ratio, and by the sample rate of the file if it differs from ours
+        let pitch = self.voice.freq.value() / self.root_freq * exp2(self.params.tune.value() / 12.0);
+        self.position += self.direction * pitch * sample_rate / self.sample_rate;
+
+        // Loops only make sense while the key can still stop the sound
+        let loop_mode = if self.gated { LoopMode::from_value(self.params.loop_mode.value()) } else { LoopMode::Off };
+        if self.position > end || self.position < start {
+            match loop_mode {
+                LoopMode::Off => self.stop(),
+                LoopMode::Forward => {
+                    let length = (end - start).max(1.0);
+                    self.position = if self.direction > 0.0 { start + (self.position - end) % length } else { end - (start - self.position) % length };
+                },
+                LoopMode::PingPong => {
+                    self.direction = -self.direction;
+                    self.position = self.position.clamp(start, end);
+                },
+            }
+        }
+
+        [left * level, right * level].into()
+    }
+}
+
+pub fn sampler_voice(voice: &Voice, keymap: &Arc<Keymap>, params: &SamplerParams, reference: &Param) -> An<SamplerVoice> {
+    An(SamplerVoice::new(voice, keymap, params, reference))
+}
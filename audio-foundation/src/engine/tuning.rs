@@ -0,0 +1,230 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use super::params::Param;
+
+// Lines of a Scala file without the comments (they start with "!")
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines().enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.starts_with('!'))
+}
+
+// The first word of a line, anything after it is a comment
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn number<T: std::str::FromStr>(line: Option<(usize, &str)>, what: &str) -> Result<T, Box<dyn Error>> {
+    let (number, line) = line.ok_or_else(|| format!("missing the {what}"))?;
+    first_word(line).parse().map_err(|_| format!("line {number}: {what} expected, found \"{line}\"").into())
+}
+
+// A Scala scale (.scl): the pitches of the scale degrees in cents above the first one, which isn't listed.
+// The last pitch is the period the scale repeats at, usually the octave.
+#[derive(Clone, Debug)]
+pub struct Scale {
+    pub description: String,
+    pitches: Vec<f64>,
+}
+
+impl Scale {
+    pub fn equal(steps: usize) -> Self {
+        Self {
+            description: format!("{steps} tone equal temperament"),
+            pitches: (1..=steps).map(|step| 1200.0 * step as f64 / steps as f64).collect(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        Self::parse(&fs::read_to_string(path)?).map_err(|err| format!("{}: {err}", path.display()).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut lines = lines(text);
+        // The description can be empty, but the line has to be there
+        let description = lines.next().ok_or("the file is empty")?.1.to_string();
+        let count: usize = number(lines.next(), "number of notes")?;
+        let mut pitches = Vec::with_capacity(count);
+        for (number, line) in lines.filter(|(_, line)| !line.is_empty()).take(count) {
+            let word = first_word(line);
+            // Cents have a period in them, everything else is a ratio like 3/2 or just 2
+            let cents = if word.contains('.') {
+                word.parse::<f64>().ok()
+            } else {
+                let (numerator, denominator) = word.split_once('/').unwrap_or((word, "1"));
+                match (numerator.parse::<f64>(), denominator.parse::<f64>()) {
+                    (Ok(numerator), Ok(denominator)) if numerator > 0.0 && denominator > 0.0 => {
+                        Some(1200.0 * (numerator / denominator).log2())
+                    },
+                    _ => None,
+                }
+            };
+            pitches.push(cents.ok_or_else(|| format!("line {number}: \"{word}\" is not a pitch"))?);
+        }
+        if pitches.len() != count {
+            return Err(format!("{count} notes announced but {} found", pitches.len()).into());
+        }
+        if count == 0 {
+            return Err("the scale has no notes".into());
+        }
+        Ok(Self { description, pitches })
+    }
+
+    // Cents of any scale degree, counting from degree 0 and going on past the period
+    fn cents(&self, degree: i64) -> f64 {
+        let size = self.pitches.len() as i64;
+        let period = self.pitches[self.pitches.len() - 1];
+        let (repeats, step) = (degree.div_euclid(size), degree.rem_euclid(size));
+        repeats as f64 * period + if step == 0 { 0.0 } else { self.pitches[step as usize - 1] }
+    }
+}
+
+// A Scala keyboard mapping (.kbm): which scale degree each MIDI note plays and the frequency of a reference note
+#[derive(Clone, Debug)]
+pub struct KeyboardMap {
+    first: u8,
+    last: u8,
+    // The note that plays scale degree 0
+    middle: i64,
+    reference_note: u8,
+    pub reference_frequency: f64,
+    // Degree that the mapping moves up by when it repeats
+    octave_degree: i64,
+    // Degree for every key of one repeat of the mapping, None for keys that don't play.
+    // Empty maps every key to the next degree.
+    mapping: Vec<Option<i64>>,
+}
+
+impl Default for KeyboardMap {
+    // Every key plays the next degree, degree 0 on middle C and A above it at 440 Hz
+    fn default() -> Self {
+        Self {
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        Self::parse(&fs::read_to_string(path)?).map_err(|err| format!("{}: {err}", path.display()).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut lines = lines(text).filter(|(_, line)| !line.is_empty());
+        let size: usize = number(lines.next(), "map size")?;
+        let first = number(lines.next(), "first note")?;
+        let last = number(lines.next(), "last note")?;
+        let middle = number(lines.next(), "middle note")?;
+        let reference_note = number(lines.next(), "reference note")?;
+        let reference_frequency: f64 = number(lines.next(), "reference frequency")?;
+        let octave_degree = number(lines.next(), "octave degree")?;
+        let mut mapping = Vec::with_capacity(size);
+        for _ in 0..size {
+            let line = lines.next();
+            // An x is a key that plays nothing
+            mapping.push(match line {
+                Some((_, line)) if first_word(line) == "x" => None,
+                line => Some(number(line, "scale degree or x")?),
+            });
+        }
+        if reference_frequency <= 0.0 {
+            return Err("the reference frequency has to be above 0".into());
+        }
+        Ok(Self { first, last, middle, reference_note, reference_frequency, octave_degree, mapping })
+    }
+
+    // Scale degree of a key, counting from the degree of the middle note
+    fn degree(&self, note: u8, scale: &Scale) -> Option<i64> {
+        if note < self.first || note > self.last {
+            return None;
+        }
+        let offset = note as i64 - self.middle;
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+        let size = self.mapping.len() as i64;
+        let octave = if self.octave_degree > 0 { self.octave_degree } else { scale.pitches.len() as i64 };
+        let degree = self.mapping[offset.rem_euclid(size) as usize]?;
+        Some(offset.div_euclid(size) * octave + degree)
+    }
+}
+
+// Where a preset gets its tuning from, no scale is 12 tone equal temperament
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TuningSettings {
+    pub scale: Option<PathBuf>,
+    pub keymap: Option<PathBuf>,
+}
+
+// Turns MIDI notes into frequencies. The frequencies are stored relative to the reference note,
+// which plays at the frequency of the reference parameter, so that can be changed while playing.
+pub struct Tuning {
+    name: String,
+    settings: TuningSettings,
+    ratios: [Option<f64>; 128],
+    reference: Param,
+}
+
+impl Tuning {
+    // Sets the reference parameter to the frequency in the keyboard map
+    pub fn new(scale: &Scale, keymap: &KeyboardMap, reference: &Param) -> Result<Self, Box<dyn Error>> {
+        let reference_cents = keymap.degree(keymap.reference_note, scale)
+            .map(|degree| scale.cents(degree))
+            .ok_or("the reference note has no scale degree")?;
+        let mut ratios = [None; 128];
+        for (note, ratio) in ratios.iter_mut().enumerate() {
+            *ratio = keymap.degree(note as u8, scale).map(|degree| ((scale.cents(degree) - reference_cents) / 1200.0).exp2());
+        }
+        reference.set(keymap.reference_frequency);
+        Ok(Self { name: scale.description.clone(), settings: TuningSettings::default(), ratios, reference: reference.clone() })
+    }
+
+    // The usual 12 tone equal temperament with A at the reference frequency
+    pub fn equal(reference: &Param) -> Self {
+        Self::new(&Scale::equal(12), &KeyboardMap::default(), reference).unwrap()
+    }
+
+    pub fn load(settings: &TuningSettings, reference: &Param) -> Result<Self, Box<dyn Error>> {
+        let scale = match &settings.scale {
+            Some(path) => Scale::load(path)?,
+            None => Scale::equal(12),
+        };
+        let keymap = match &settings.keymap {
+            Some(path) => KeyboardMap::load(path)?,
+            None => KeyboardMap::default(),
+        };
+        let mut tuning = Self::new(&scale, &keymap, reference)?;
+        tuning.settings = settings.clone();
+        Ok(tuning)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn settings(&self) -> &TuningSettings {
+        &self.settings
+    }
+
+    // None for keys the keyboard map leaves out
+    pub fn frequency(&self, note: u8) -> Option<f64> {
+        self.ratio(note).map(|ratio| ratio * self.reference.value())
+    }
+
+    // The frequency of a note relative to the reference note
+    pub fn ratio(&self, note: u8) -> Option<f64> {
+        self.ratios.get(note as usize).copied().flatten()
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use fundsp::hacker::*;
+
+use super::tuning::Tuning;
+
+// The controls of one voice in the audio graph.
+// The gate holds a new positive number for every note on (so a reused voice retriggers) and 0.0 for note off.
+#[derive(Clone)]
+pub struct Voice {
+    // The MIDI note, for voices that care about the key more than the pitch, like the sampler
+    pub note: Shared<f64>,
+    pub freq: Shared<f64>,
+    pub gate: Shared<f64>,
+    pub velocity: Shared<f64>,
+}
+
+impl Voice {
+    fn new() -> Self {
+        Self {
+            note: shared(69.0),
+            freq: shared(440.0),
+            gate: shared(0.0),
+            velocity: shared(0.0),
+        }
+    }
+}
+
+// A note starting with its velocity from 0.0 to 1.0, or ending
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteEvent {
+    On(u8, f64),
+    Off(u8),
+}
+
+// Hands out voices to notes. When all voices are busy the oldest note is stolen.
+pub struct VoiceAllocator {
+    voices: Vec<Voice>,
+    notes: Vec<Option<u8>>,
+    started: Vec<u64>,
+    counter: u64,
+    // Gate and velocity of the most recent note, for things that are not per voice like the modulation matrix
+    gate: Shared<f64>,
+    velocity: Shared<f64>,
+    tuning: Arc<Tuning>,
+}
+
+impl VoiceAllocator {
+    pub fn new(count: usize, tuning: &Arc<Tuning>) -> Self {
+        Self {
+            voices: (0..count).map(|_| Voice::new()).collect(),
+            notes: vec![None; count],
+            started: vec![0; count],
+            counter: 0,
+            gate: shared(0.0),
+            velocity: shared(0.0),
+            tuning: Arc::clone(tuning),
+        }
+    }
+
+    // Notes that are already sounding keep their pitch. Returns the old tuning, so it can be freed off the audio thread.
+    pub fn set_tuning(&mut self, tuning: &Arc<Tuning>) -> Arc<Tuning> {
+        std::mem::replace(&mut self.tuning, Arc::clone(tuning))
+    }
+
+    pub fn voices(&self) -> &[Voice] {
+        &self.voices
+    }
+
+    pub fn gate(&self) -> &Shared<f64> {
+        &self.gate
+    }
+
+    pub fn velocity(&self) -> &Shared<f64> {
+        &self.velocity
+    }
+
+    // Velocity goes from 0.0 to 1.0, notes the tuning leaves out don't play
+    pub fn note_on(&mut self, note: u8, velocity: f64) {
+        let Some(freq) = self.tuning.frequency(note) else { return };
+        self.counter += 1;
+        let index = self.notes.iter().position(|n| *n == Some(note))
+            .or_else(|| self.notes.iter().position(|n| n.is_none()))
+            .unwrap_or_else(|| {
+                (0..self.voices.len()).min_by_key(|i| self.started[*i]).unwrap()
+            });
+
+        let voice = &self.voices[index];
+        voice.note.set_value(note as f64);
+        voice.freq.set_value(freq);
+        voice.velocity.set_value(velocity);
+        voice.gate.set_value(self.counter as f64);
+        self.notes[index] = Some(note);
+        self.started[index] = self.counter;
+
+        self.gate.set_value(self.counter as f64);
+        self.velocity.set_value(velocity);
+    }
+
+    pub fn play(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::On(note, velocity) => self.note_on(note, velocity),
+            NoteEvent::Off(note) => self.note_off(note),
+        }
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        for (voice, n) in self.voices.iter().zip(self.notes.iter_mut()) {
+            if *n == Some(note) {
+                voice.gate.set_value(0.0);
+                *n = None;
+            }
+        }
+        if self.notes.iter().all(|n| n.is_none()) {
+            self.gate.set_value(0.0);
+        }
+    }
+}
@@ -0,0 +1,4 @@
+// The sound engine as a library, for the app in main.rs and for the CLAP plugin (the "clap" feature)
+pub mod engine;
+#[cfg(feature = "clap")]
+mod plugin;
@@ -1,11 +1,63 @@
 use std::io::{stdin, stdout, Write};
-use std::{num::NonZeroU32, sync::mpsc, time::Instant};
+use std::{num::NonZeroU32, sync::{mpsc, Arc}, time::Instant};
 use winit::{event::*, event_loop::{ControlFlow, EventLoop}, keyboard::{PhysicalKey, KeyCode}, window::{Window, WindowBuilder}};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::traits::{DeviceTrait, StreamTrait};
 use fundsp::hacker::*;
 use midir::{Ignore, MidiInput};
 use softbuffer::{Context, Surface};
 
+use audio_foundation::engine;
+use engine::analysis::Analyzer;
+use engine::drums::{default_pattern, DrumKind, DrumPattern, DrumTriggers, PATTERN_STEPS};
+use engine::effects::{EffectKind, EffectOrder};
+use engine::envelope::{live_adsr, AdsrParams};
+use engine::input::{start_input_stream, AudioInput};
+use engine::instruments::{Instruments, LeadOscillator, VOICES};
+use engine::filter::{multi_filter, FilterMode, FilterParams};
+use engine::fm::ALGORITHMS;
+use engine::granular::Granular;
+use engine::hotswap::hot_swap;
+use engine::load::{CallbackTimer, LoadHistory, LoadMeter};
+use engine::looper::Looper;
+use engine::mixer::{Mixer, OUTPUT_PAIRS};
+use engine::output::{find_output_device, AudioOutput, Render};
+use engine::params::ParamRegistry;
+use engine::patch::{patch_synth, Patch};
+use engine::physical::{modal_voice, plucked_string, Excitation, ModalModel, ModalParams, StringParams};
+use engine::pitch::{NoteFollower, Yin};
+use engine::preset::Preset;
+use engine::recorder::Recorder;
+use engine::sampler::{sampler_voice, Keymap, Sample, SamplerParams};
+use engine::tuning::{Tuning, TuningSettings};
+use engine::voice::{NoteEvent, VoiceAllocator};
+
+// The modulation matrix is updated once per block of this many frames
+const CONTROL_BLOCK: usize = 64;
+// Mixer tracks and the MIDI channel (counted from 0) their volume and pan controllers come in on.
+// Channel 9 is left out, all its controllers set the cutoff.
+const TRACKS: [(&str, u8); 11] = [
+    ("lead", 0), ("bass", 1), ("drums", 2), ("sampler", 3), ("input", 4), ("click", 5), ("pad", 6), ("grains", 7),
+    ("string", 10), ("modal", 11), ("patch", 12),
+];
+const PRESET_PATH: &str = "presets/default.ron";
+// The text patch on channel 13, see engine/patch.rs for how they're written
+const PATCH_PATH: &str = "patches/default.patch";
+// WAV files for the sampler, pads start at the note of the kick like on most pad controllers
+const SAMPLE_FOLDER: &str = "samples";
+const FIRST_PAD: u8 = 36;
+const RECORDING_FOLDER: &str = "recordings";
+// A press of this controller starts or stops the recorder
+const RECORD_CC: u8 = 29;
+// The looper loops one bar of 4/4, and can snap notes to these grids (in beats)
+const LOOP_BEATS: f64 = 4.0;
+const QUANTIZE_OPTIONS: [Option<f64>; 4] = [None, Some(1.0), Some(0.5), Some(0.25)];
+// The DSP load is logged this often (seconds)
+const LOAD_LOG_INTERVAL: usize = 10;
+// Number of spectrum bars on screen
+const ANALYSIS_BANDS: usize = 24;
+// The metronome plays on this output pair (channels 3/4), as a cue for headphones
+const CLICK_OUTPUT: f64 = 1.0;
+
 
 struct State<'a> {
     window: &'a Window,
@@ -16,6 +68,13 @@ struct State<'a> {
     bpm: f64,
     tempo_index: usize,
     tempo_options: Vec<f64>,
+    playing: Option<u8>,
+    // Transport position in beats at transport_time, see beats()
+    transport_beats: f64,
+    transport_time: Instant,
+    drum_pattern: DrumPattern,
+    drum_step: Option<usize>,
+    click_beat: Option<usize>,
 }
 
 impl<'a> State<'a> {
@@ -41,6 +100,12 @@ impl<'a> State<'a> {
             bpm: 120.0,
             tempo_index: 4,
             tempo_options,
+            playing: None,
+            transport_beats: 0.0,
+            transport_time: start_time,
+            drum_pattern: default_pattern(),
+            drum_step: None,
+            click_beat: None,
         }
     }
 
@@ -60,13 +125,62 @@ impl<'a> State<'a> {
         if phasor < 0.2 { 1.0 } else { 0.0 }
     }
 
+    // Turns the sequencer into note on and note off events for the voices
+    fn update_sequencer(&mut self, voices: &mut VoiceAllocator) {
+        let note = self.get_sequencer_value() as u8;
+        let gate = self.trigger_envelope() > 0.0;
+        if self.playing.is_some() && (!gate || self.playing != Some(note)) {
+            voices.note_off(self.playing.take().unwrap());
+        }
+        if gate && self.playing.is_none() {
+            voices.note_on(note, 0.8);
+            self.playing = Some(note);
+        }
+    }
+
+    // Transport position in beats since the start. It moves at the sequencer tempo and a new tempo only changes
+    // how fast it moves from then on, so the looper and the drums don't jump.
+    fn beats(&self) -> f64 {
+        self.transport_beats + self.transport_time.elapsed().as_secs_f64() * self.bpm * self.tempo_options[self.tempo_index] / 60.0
+    }
+
+    // Moves the transport up to now at the current tempo, every frame and before the tempo changes
+    fn advance_transport(&mut self) {
+        self.transport_beats = self.beats();
+        self.transport_time = Instant::now();
+    }
+
+    // Plays the drum pattern, one bar is four beats
+    fn update_drums(&mut self, drums: &DrumTriggers) {
+        let step = (self.beats() * 4.0) as usize % PATTERN_STEPS;
+        if self.drum_step == Some(step) {
+            return;
+        }
+        self.drum_step = Some(step);
+        drums.play_step(&self.drum_pattern, step);
+    }
+
+    // Ticks the metronome on every beat, higher on the first beat of the bar
+    fn update_click(&mut self, gate: &Shared<f64>, pitch: &Shared<f64>) {
+        let beat = self.beats() as usize;
+        if self.click_beat == Some(beat) {
+            return;
+        }
+        self.click_beat = Some(beat);
+        pitch.set_value(if beat.is_multiple_of(4) { 1760.0 } else { 880.0 });
+        // Every beat is a new value, so the envelope starts again
+        gate.set_value(beat as f64 + 1.0);
+    }
+
     fn increase_tempo(&mut self) {
+        self.advance_transport();
         if self.tempo_index != self.tempo_options.len() - 1 {
             self.tempo_index += 1;
         }
     }
 
     fn decrease_tempo(&mut self) {
+        self.advance_transport();
         if self.tempo_index != 0 {
             self.tempo_index -= 1;
         }
@@ -88,49 +202,238 @@ impl<'a> State<'a> {
     }
 }
 
+// Filters the stereo input with one filter per side
+fn input_filter(filter: &FilterParams) -> Net64 {
+    let side = || (pass() | var(filter.cutoff.shared()) | dc(midi_hz(60.0))) >> multi_filter(filter);
+    Net64::wrap(Box::new(side() | side()))
+}
+
+// One hue per pitch class around the color wheel, C is red
+fn note_color(note: u8) -> u32 {
+    let hue = (note % 12) as f64 / 2.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, x, 0.0), 1 => (x, 1.0, 0.0), 2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0), 4 => (x, 0.0, 1.0), _ => (1.0, 0.0, x),
+    };
+    ((r * 255.0) as u32) << 16 | ((g * 255.0) as u32) << 8 | (b * 255.0) as u32
+}
+
+fn toggle_recording(recorder: &Recorder) {
+    if recorder.is_recording() {
+        let dropped = recorder.stop();
+        println!("Recording stopped");
+        if dropped > 0 {
+            eprintln!("{dropped} frames were lost, the disk was too slow");
+        }
+    } else {
+        recorder.start();
+    }
+}
+
 fn main() {
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("No output device available.");    
+    // Pass --input <file.wav> to play a file into the input track instead of the input device
+    let args: Vec<String> = std::env::args().collect();
+    let input_file = args.iter().position(|arg| arg == "--input").and_then(|i| args.get(i + 1)).cloned();
+    // and --output <name> to play on the output device whose name contains that text
+    let output_name = args.iter().position(|arg| arg == "--output").and_then(|i| args.get(i + 1)).cloned();
+    // and --grains <file.wav> to give the granular engine a sample, without one it takes grains from the input
+    let grain_file = args.iter().position(|arg| arg == "--grains").and_then(|i| args.get(i + 1)).cloned();
+    // and --scale <file.scl> and --keymap <file.kbm> for another tuning than 12 tone equal temperament
+    let tuning_settings = TuningSettings {
+        scale: args.iter().position(|arg| arg == "--scale").and_then(|i| args.get(i + 1)).map(Into::into),
+        keymap: args.iter().position(|arg| arg == "--keymap").and_then(|i| args.get(i + 1)).map(Into::into),
+    };
+
+    let device = find_output_device(output_name.as_deref()).expect("No output device available.");
     let config = device.default_output_config().expect("No default output config found.");
-    println!("Output device: {:?}", device.name()); 
     println!("Default output config: {:#?}", config);
-    let sample_format = config.sample_format();
-    println!("Expected sample format: {}", sample_format);
-    let stream_config: cpal::StreamConfig = config.into();
-    println!("Stream config: {:#?}", stream_config);
-    let sample_rate = stream_config.sample_rate.0 as f64;
-    let channels = stream_config.channels as usize;
-
-    let freq = shared(440.0);
-    let modulator = shared(5.0);
-    let trigger = shared(0.0);
-    let cutoff = shared(800.0);
-
-    let fm_synth = oversample(var(&freq) >> sine() * var(&freq) * var(&modulator) + var(&freq) >> sine());
-    let filter = (pass() | var(&cutoff) | dc(0.8)) >> lowrez();
-    let env = var(&trigger) >> adsr_live(0.002, 0.0, 1.0, 0.1);
-    let synth= fm_synth >> filter * env;
-
-    let mut node = synth * 0.2;
-    node.set_sample_rate(sample_rate);
-
-    let mut next_value = move || node.get_stereo();
-    let audio_callback = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        for frame in data.chunks_mut(channels) {
-            let (l, r) = next_value();
-            frame[0] = l as f32;
-            if channels > 1 { frame[1] = r as f32; }
-        }
+    // Everything runs at this rate, also after switching to another device
+    let sample_rate = config.sample_rate().0 as f64;
+
+    let bpm = shared(120.0);
+    let params = ParamRegistry::new();
+
+    // Frequency of the reference note of the tuning, A4 unless a keyboard map says otherwise
+    let reference = params.add("tuning.reference", 20.0, 2000.0, 440.0);
+    let mut tuning = Arc::new(Tuning::load(&tuning_settings, &reference).unwrap_or_else(|err| {
+        eprintln!("Could not load the tuning: {err}");
+        Tuning::equal(&reference)
+    }));
+    println!("Tuning: {}", tuning.name());
+    let mut mixer = Mixer::new(&params, &bpm);
+    mixer.set_sample_rate(sample_rate);
+    // Lead on channel 1, bass on 2, drums on 10 and the pad on 7
+    let Instruments { mut voices, mut bass_voices, mut pad_voices, drums, fm, filter, lead, matrix } = Instruments::new(&params, &mut mixer, &bpm, &tuning);
+
+    let keymap = match Keymap::load_folder(SAMPLE_FOLDER, FIRST_PAD) {
+        Ok(keymap) => {
+            for zone in keymap.zones() {
+                println!("Sample {} on notes {}-{} (root {})", zone.sample.name, zone.low, zone.high, zone.root);
+            }
+            keymap
+        },
+        Err(err) => {
+            eprintln!("No samples loaded from {SAMPLE_FOLDER}: {err}");
+            Keymap::default()
+        },
+    };
+    keymap.set_tuning(&tuning);
+    let keymap = Arc::new(keymap);
+    let mut sampler_voices = VoiceAllocator::new(VOICES, &tuning);
+    let sampler_params = SamplerParams::new(&params, "sampler");
+    let sampler = sampler_voices.voices().iter()
+        .map(|voice| Net64::wrap(Box::new(sampler_voice(voice, &keymap, &sampler_params, &reference))))
+        .reduce(|a, b| a + b)
+        .unwrap();
+    mixer.add_track(&params, TRACKS[3].0, Box::new(sampler * 0.5));
+
+    let (mut input, input_stream) = match input_file.map(Sample::load) {
+        Some(Ok(sample)) => {
+            println!("Input from file {}", sample.name);
+            (AudioInput::file(&params, "input", sample), None)
+        },
+        other => {
+            if let Some(Err(err)) = other {
+                eprintln!("Could not load the input file: {err}");
+            }
+            let (input, producer) = AudioInput::device(&params, "input", sample_rate);
+            let stream = start_input_stream(sample_rate, producer)
+                .map_err(|err| eprintln!("Audio input is off: {err}"))
+                .ok();
+            (input, stream)
+        },
     };
+    let input_level = input.level();
+    let (mut input_analyzer, input_tap) = Analyzer::new(sample_rate, ANALYSIS_BANDS, Yin::window_length(sample_rate));
+    input.set_tap(input_tap);
+    let grain_sample = grain_file.map(Sample::load).and_then(|sample| {
+        sample.map_err(|err| eprintln!("Could not load the grain sample: {err}")).ok()
+    });
+    let (granular, live_output) = Granular::new(&params, "grains", grain_sample.map(Arc::new));
+    input.add_live_output(live_output);
+    // The modal resonators can be played by the input too, through a ring buffer of their own
+    let (modal_input, modal_producer) = AudioInput::device(&params, "modal.input", sample_rate);
+    input.add_live_output(modal_producer);
+    let grain_activity = granular.activity();
+    let grain_freeze = granular.params().freeze.clone();
+    let input_filter_params = FilterParams::new(&params, "input.filter");
+    input_filter_params.cutoff.set(4000.0);
+    let input_track = mixer.add_track(&params, TRACKS[4].0, Box::new(An(input) >> input_filter(&input_filter_params)));
+    // Muted at first so a microphone doesn't feed back into the speakers
+    input_track.mute.set(1.0);
+
+    let click_gate = shared(0.0);
+    let click_pitch = shared(880.0);
+    let click_env = AdsrParams::new(&params, "click.env", 0.001, 0.03, 0.0, 0.03);
+    let click = (var(&click_pitch) >> sine()) * ((var(&click_gate) | dc(1.0)) >> live_adsr(&click_env));
+    let click_track = mixer.add_track(&params, TRACKS[5].0, Box::new(click * 0.3));
+    click_track.output.set(CLICK_OUTPUT);
+    // Off until it's asked for, on a stereo device the cue output doesn't exist anyway
+    click_track.mute.set(1.0);
+
+    let grains_track = mixer.add_track(&params, TRACKS[7].0, Box::new(An(granular) * 0.5));
+    grains_track.send_a.set(0.4);
+    // Muted for the same reason as the input, the grains could come from a microphone
+    grains_track.mute.set(1.0);
+
+    // Plucked strings on channel 11 and struck bells, bars and drum skins on channel 12
+    let mut string_voices = VoiceAllocator::new(VOICES, &tuning);
+    let string_params = StringParams::new(&params, "string");
+    let strings = string_voices.voices().iter()
+        .map(|voice| Net64::wrap(Box::new((var(&voice.freq) | var(&voice.gate) | var(&voice.velocity)) >> plucked_string(&string_params))))
+        .reduce(|a, b| a + b)
+        .unwrap();
+    mixer.add_track(&params, TRACKS[8].0, Box::new(strings * 0.3));
+    let mut modal_voices = VoiceAllocator::new(VOICES, &tuning);
+    let modal = ModalParams::new(&params, "modal");
+    // Every voice hears the input in mono, it only rings the ones with a note held
+    let modal_bank = modal_voices.voices().iter()
+        .map(|voice| Net64::wrap(Box::new((var(&voice.freq) | var(&voice.gate) | var(&voice.velocity) | pass()) >> modal_voice(&modal))))
+        .reduce(|a, b| a & b)
+        .unwrap();
+    let modal_track = mixer.add_track(&params, TRACKS[9].0, Box::new(An(modal_input) >> join::<U2>() >> (modal_bank * 0.3)));
+    modal_track.send_a.set(0.3);
+
+    // Channel 13 plays the patch from PATCH_PATH, D loads it again after it was edited
+    let mut patch_voices = VoiceAllocator::new(VOICES, &tuning);
+    let patch = match Patch::load(PATCH_PATH, &params, "patch") {
+        Ok(patch) => patch_synth(&patch, patch_voices.voices()),
+        Err(err) => {
+            eprintln!("Could not load the patch: {err}");
+            Net64::wrap(Box::new(dc((0.0, 0.0))))
+        },
+    };
+    let (patch, patch_swapper) = hot_swap(Box::new(patch));
+    mixer.add_track(&params, TRACKS[10].0, Box::new(Net64::wrap(Box::new(patch)) * 0.3));
+
+    let limiter_reduction = mixer.limiter_reduction();
+    // The meter falls back slowly, so short peaks of gain reduction can be seen
+    let mut shown_reduction: f64 = 0.0;
+    let mut effect_order = mixer.master_mut().order();
+    let (effect_sender, effect_receiver) = mpsc::channel::<EffectOrder>();
+
+    // Knobs on any channel except 9 (which sets the cutoff below)
+    for (cc, name) in [
+        (20, "fx.drive.mix"), (21, "fx.chorus.mix"), (22, "fx.delay.mix"), (23, "fx.reverb.mix"),
+        (24, "fx.delay.time"), (25, "fx.delay.feedback"), (26, "fx.reverb.size"), (27, "filter.resonance"),
+    ] {
+        params.map_cc(cc, name);
+    }
+
+    let (mut analyzer, mut analysis_tap) = Analyzer::new(sample_rate, ANALYSIS_BANDS, Yin::window_length(sample_rate));
+
+    // The pitch of the input (or of the output) can play the lead voices
+    let mut yin = Yin::new(sample_rate);
+    let mut note_follower = NoteFollower::new(0.8);
+    let mut audio_to_midi = false;
+    let mut pitch_from_output = false;
+
+    // Records what is played on channel 1 and plays it back on the same voices
+    let mut looper = Looper::new(LOOP_BEATS);
+    let mut quantize_index = 0;
+
+    let (recorder, mut recorder_tap) = Recorder::new(RECORDING_FOLDER, sample_rate);
 
-    let err_fn = |err| eprintln!("An error occurred on the audio stream: {}", err);
+    let load_meter = LoadMeter::new();
+    let mut callback_timer = CallbackTimer::new(&load_meter, sample_rate);
+    let mut load_history = LoadHistory::new(&load_meter);
+    let mut load_seconds = 0;
 
-    let stream = match sample_format {
-        cpal::SampleFormat::F32 => device.build_output_stream(&stream_config, audio_callback, err_fn, None),
-        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
-    }.expect("Could not build f32 output stream.");
+    let audio_matrix = Arc::clone(&matrix);
+    let render: Render = Box::new(move |data: &mut [f32], channels: usize, info: &cpal::OutputCallbackInfo| {
+        callback_timer.begin(info, data.len() / channels);
+        while let Ok(order) = effect_receiver.try_recv() {
+            mixer.master_mut().set_order(&order);
+        }
+        for block in data.chunks_mut(channels * CONTROL_BLOCK) {
+            // Skip the update instead of waiting if the UI is editing the matrix right now
+            if let Ok(mut matrix) = audio_matrix.try_lock() {
+                matrix.tick((block.len() / channels) as f64 / sample_rate);
+            }
+            for frame in block.chunks_mut(channels) {
+                // One output pair for every two channels, a mono device only gets the left side
+                let mut outputs = [(0.0, 0.0); OUTPUT_PAIRS];
+                let pairs = std::cmp::min(channels.div_ceil(2), OUTPUT_PAIRS);
+                let (l, r) = mixer.process(&mut outputs[..pairs]);
+                analysis_tap.push(l, r);
+                recorder_tap.push(l as f32, r as f32);
+                // Channels beyond the last pair play silence
+                frame.fill(0.0);
+                for (channels, (l, r)) in frame.chunks_mut(2).zip(outputs) {
+                    channels[0] = l as f32;
+                    if let Some(right) = channels.get_mut(1) { *right = r as f32; }
+                }
+            }
+        }
+        callback_timer.end();
+    });
 
-    stream.play().expect("Could not start audio stream.");
+    let mut output = AudioOutput::new(render, sample_rate, &load_meter);
+    output.start(&device).expect("Could not start audio stream.");
+    if let Some(input_stream) = &input_stream {
+        input_stream.play().expect("Could not start audio input stream.");
+    }
     println!("Audio pipeline is running.");
 
     let (sender, receiver) = mpsc::channel();
@@ -195,8 +498,74 @@ fn main() {
             if message[0] == 184 {
                 let input = message[2] as f64 / 128.0;
                 let val = xerp11(100.0, 4000.0, input);
-                cutoff.set_value(val);
+                filter.cutoff.set(val);
                 println!("Cutoff is now: {val}");
+            } else if message[0] & 0xF0 == 0xB0 && matches!(message[1], 7 | 10) && let Some((track, _)) = TRACKS.iter().find(|(_, channel)| *channel == message[0] & 0x0F) {
+                // Volume and pan on the channel of the track
+                let name = if message[1] == 7 { "volume" } else { "pan" };
+                let param = params.get(&format!("track.{track}.{name}")).unwrap();
+                param.set_normalized(message[2] as f64 / 127.0);
+                println!("{} is now: {:.3}", param.name(), param.get());
+            } else if message[0] & 0xF0 == 0xB0 && message[1] == RECORD_CC {
+                if message[2] > 0 {
+                    toggle_recording(&recorder);
+                }
+            } else if message[0] & 0xF0 == 0xB0 && let Some(param) = params.handle_cc(message[1], message[2]) {
+                println!("{} is now: {:.3}", param.name(), param.get());
+            }
+            // Note on and note off on channel 1 play the voices directly, and go to the looper
+            let event = match message[0] {
+                144 if message[2] > 0 => Some(NoteEvent::On(message[1], message[2] as f64 / 127.0)),
+                128 | 144 => Some(NoteEvent::Off(message[1])),
+                _ => None,
+            };
+            if let Some(event) = event {
+                voices.play(event);
+                looper.record(state.beats(), event);
+            }
+            // Channel 2 plays the bass
+            if message[0] == 145 && message[2] > 0 {
+                bass_voices.note_on(message[1], message[2] as f64 / 127.0);
+            }
+            if message[0] == 129 || (message[0] == 145 && message[2] == 0) {
+                bass_voices.note_off(message[1]);
+            }
+            // Channel 4 plays the sampler
+            if message[0] == 147 && message[2] > 0 {
+                sampler_voices.note_on(message[1], message[2] as f64 / 127.0);
+            }
+            if message[0] == 131 || (message[0] == 147 && message[2] == 0) {
+                sampler_voices.note_off(message[1]);
+            }
+            // Channel 7 plays the pad
+            if message[0] == 150 && message[2] > 0 {
+                pad_voices.note_on(message[1], message[2] as f64 / 127.0);
+            }
+            if message[0] == 134 || (message[0] == 150 && message[2] == 0) {
+                pad_voices.note_off(message[1]);
+            }
+            // Channel 11 plays the strings, channel 12 the modal resonators and channel 13 the patch
+            if message[0] == 154 && message[2] > 0 {
+                string_voices.note_on(message[1], message[2] as f64 / 127.0);
+            }
+            if message[0] == 138 || (message[0] == 154 && message[2] == 0) {
+                string_voices.note_off(message[1]);
+            }
+            if message[0] == 155 && message[2] > 0 {
+                modal_voices.note_on(message[1], message[2] as f64 / 127.0);
+            }
+            if message[0] == 139 || (message[0] == 155 && message[2] == 0) {
+                modal_voices.note_off(message[1]);
+            }
+            if message[0] == 156 && message[2] > 0 {
+                patch_voices.note_on(message[1], message[2] as f64 / 127.0);
+            }
+            if message[0] == 140 || (message[0] == 156 && message[2] == 0) {
+                patch_voices.note_off(message[1]);
+            }
+            // Channel 10 is the drum channel like in General MIDI, drums ignore note off
+            if message[0] == 153 && message[2] > 0 && let Some(kind) = DrumKind::from_note(message[1]) {
+                drums.hit(kind, message[2] as f64 / 127.0);
             }
             if message[0] == 152 {
                 let midi_note = message[1] as f64;
@@ -216,16 +585,18 @@ fn main() {
                     },
                     WindowEvent::CursorMoved { position, .. } => {
                         let y = position.y / state.window.inner_size().height as f64;
-                        modulator.set_value(y * 10.0);
+                        fm.index.set(y * 10.0);
                     },
                     WindowEvent::KeyboardInput { event, .. } => {
                         match (event.physical_key, event.state) {
                             (PhysicalKey::Code(KeyCode::ArrowUp), ElementState::Pressed) => {
                                 state.bpm += 1.0;
+                                bpm.set_value(state.bpm);
                                 state.print_state();
                             },
                             (PhysicalKey::Code(KeyCode::ArrowDown), ElementState::Pressed) => {
                                 state.bpm -= 1.0;
+                                bpm.set_value(state.bpm);
                                 state.print_state();
                             },
                             (PhysicalKey::Code(KeyCode::ArrowRight), ElementState::Pressed) => {
@@ -244,12 +615,214 @@ fn main() {
                             (PhysicalKey::Code(KeyCode::KeyA), ElementState::Released) => {
                                 println!("Key A Released.");
                             }
+                            (PhysicalKey::Code(KeyCode::KeyF), ElementState::Pressed) => {
+                                let next = (filter.mode.get() as usize + 1) % FilterMode::ALL.len();
+                                filter.mode.set(next as f64);
+                                println!("Filter type: {:?}", FilterMode::from_value(filter.mode.get()));
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyE), ElementState::Pressed) => {
+                                effect_order.rotate_left(1);
+                                effect_sender.send(effect_order).unwrap();
+                                println!("Effects: {:?}", effect_order);
+                            },
+                            (PhysicalKey::Code(code @ (KeyCode::Digit1 | KeyCode::Digit2 | KeyCode::Digit3 | KeyCode::Digit4)), ElementState::Pressed) => {
+                                let index = match code { KeyCode::Digit1 => 0, KeyCode::Digit2 => 1, KeyCode::Digit3 => 2, _ => 3 };
+                                let name = EffectKind::ALL[index].name();
+                                let bypass = params.get(&format!("fx.{name}.bypass")).unwrap();
+                                bypass.set(1.0 - bypass.get());
+                                println!("{name}: {}", if bypass.get() >= 0.5 { "bypassed" } else { "on" });
+                            },
+                            (PhysicalKey::Code(code @ (KeyCode::F1 | KeyCode::F2 | KeyCode::F3 | KeyCode::F5 | KeyCode::F6 | KeyCode::F7)), ElementState::Pressed) => {
+                                // F1 to F3 mute, F5 to F7 solo the tracks
+                                let (index, switch) = match code {
+                                    KeyCode::F1 => (0, "mute"), KeyCode::F2 => (1, "mute"), KeyCode::F3 => (2, "mute"),
+                                    KeyCode::F5 => (0, "solo"), KeyCode::F6 => (1, "solo"), _ => (2, "solo"),
+                                };
+                                let param = params.get(&format!("track.{}.{switch}", TRACKS[index].0)).unwrap();
+                                param.set(1.0 - param.get());
+                                println!("{}: {}", param.name(), param.get() >= 0.5);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyI), ElementState::Pressed) => {
+                                input_track.mute.set(1.0 - input_track.mute.get());
+                                println!("Input monitoring: {}", input_track.mute.get() < 0.5);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyB), ElementState::Pressed) => {
+                                click_track.mute.set(1.0 - click_track.mute.get());
+                                println!("Click on channels {}/{}: {}", CLICK_OUTPUT as usize * 2 + 1, CLICK_OUTPUT as usize * 2 + 2, click_track.mute.get() < 0.5);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyG), ElementState::Pressed) => {
+                                // Moves the drums to the next output pair, for mixing them outside
+                                let output = params.get("track.drums.output").unwrap();
+                                let pair = (output.get().round() as usize + 1) % OUTPUT_PAIRS;
+                                output.set(pair as f64);
+                                if pair == 0 {
+                                    println!("Drums on the master bus");
+                                } else {
+                                    println!("Drums on channels {}/{}", pair * 2 + 1, pair * 2 + 2);
+                                }
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyH), ElementState::Pressed) => {
+                                grains_track.mute.set(1.0 - grains_track.mute.get());
+                                println!("Grains: {}", grains_track.mute.get() < 0.5);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyJ), ElementState::Pressed) => {
+                                grain_freeze.set(1.0 - grain_freeze.get());
+                                println!("Live grain buffer frozen: {}", grain_freeze.get() >= 0.5);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyV), ElementState::Pressed) => {
+                                // Steps through the ways the FM operators are connected
+                                let next = (fm.algorithm.get().round() as usize + 1) % ALGORITHMS.len();
+                                fm.algorithm.set(next as f64);
+                                println!("FM algorithm {}: {}", next + 1, fm.algorithm().name);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyW), ElementState::Pressed) => {
+                                let next = (modal.model.get().round() as usize + 1) % ModalModel::ALL.len();
+                                modal.model.set(next as f64);
+                                println!("Modal model: {:?}", ModalModel::from_value(modal.model.get()));
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyY), ElementState::Pressed) => {
+                                let next = (modal.excitation.get().round() as usize + 1) % Excitation::ALL.len();
+                                modal.excitation.set(next as f64);
+                                println!("Modal excitation: {:?}", Excitation::from_value(modal.excitation.get()));
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyD), ElementState::Pressed) => {
+                                // A patch with errors leaves the one that is playing alone
+                                match Patch::load(PATCH_PATH, &params, "patch") {
+                                    Ok(patch) => {
+                                        let voices = patch_voices.voices().to_vec();
+                                        patch_swapper.swap(move || Box::new(patch_synth(&patch, &voices)));
+                                        println!("Loaded patch {PATCH_PATH}");
+                                    },
+                                    Err(err) => eprintln!("Could not load the patch: {err}"),
+                                }
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyZ), ElementState::Pressed) => {
+                                // Crossfades to the rebuilt lead track without stopping the stream
+                                let index = LeadOscillator::ALL.iter().position(|osc| *osc == lead.oscillator()).unwrap();
+                                let next = LeadOscillator::ALL[(index + 1) % LeadOscillator::ALL.len()];
+                                lead.set_oscillator(next);
+                                println!("Lead oscillator: {:?}", next);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyX), ElementState::Pressed) => {
+                                let bypass = params.get("master.limiter.bypass").unwrap();
+                                bypass.set(1.0 - bypass.get());
+                                println!("Master limiter: {}", if bypass.get() >= 0.5 { "off, careful with the volume" } else { "on" });
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyR), ElementState::Pressed) => {
+                                toggle_recording(&recorder);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyK), ElementState::Pressed) => {
+                                if looper.is_recording() {
+                                    looper.stop_recording(state.beats());
+                                    println!("Looper: {} layers", looper.layers());
+                                } else {
+                                    looper.start_recording();
+                                    println!("Looper: recording layer {}", looper.layers() + 1);
+                                }
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyU), ElementState::Pressed) => {
+                                for event in looper.undo() {
+                                    voices.play(event);
+                                }
+                                println!("Looper: {} layers", looper.layers());
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyC), ElementState::Pressed) => {
+                                for event in looper.clear() {
+                                    voices.play(event);
+                                }
+                                println!("Looper cleared");
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyQ), ElementState::Pressed) => {
+                                quantize_index = (quantize_index + 1) % QUANTIZE_OPTIONS.len();
+                                looper.set_quantize(QUANTIZE_OPTIONS[quantize_index]);
+                                match looper.quantize() {
+                                    Some(grid) => println!("Looper quantize: {grid} beats"),
+                                    None => println!("Looper quantize: off"),
+                                }
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyP), ElementState::Pressed) => {
+                                audio_to_midi = !audio_to_midi;
+                                if let Some(event) = note_follower.stop() {
+                                    voices.play(event);
+                                }
+                                println!("Audio to MIDI: {audio_to_midi}");
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyO), ElementState::Pressed) => {
+                                pitch_from_output = !pitch_from_output;
+                                println!("Pitch tracking on the {}", if pitch_from_output { "output" } else { "input" });
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyN), ElementState::Pressed) => {
+                                match output.next_device() {
+                                    Ok(()) => println!("Playing on {}", output.device()),
+                                    Err(err) => eprintln!("Could not switch the output: {err}"),
+                                }
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyT), ElementState::Pressed) => {
+                                load_history.print(true);
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyM), ElementState::Pressed) => {
+                                matrix.lock().unwrap().print();
+                                params.print();
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyS), ElementState::Pressed) => {
+                                let preset = Preset {
+                                    name: "default".to_string(),
+                                    params: params.values(),
+                                    modulation: matrix.lock().unwrap().settings(),
+                                    effects: effect_order.to_vec(),
+                                    tuning: tuning.settings().clone(),
+                                    lead: lead.oscillator(),
+                                };
+                                match preset.save(PRESET_PATH) {
+                                    Ok(()) => println!("Saved preset to {PRESET_PATH}"),
+                                    Err(err) => eprintln!("Could not save preset: {err}"),
+                                }
+                            },
+                            (PhysicalKey::Code(KeyCode::KeyL), ElementState::Pressed) => {
+                                match Preset::load(PRESET_PATH) {
+                                    Ok(preset) => {
+                                        // Before the params, the saved reference pitch wins over the keyboard map
+                                        match Tuning::load(&preset.tuning, &reference) {
+                                            Ok(loaded) => {
+                                                tuning = Arc::new(loaded);
+                                                for allocator in [&mut voices, &mut bass_voices, &mut sampler_voices, &mut pad_voices, &mut string_voices, &mut modal_voices, &mut patch_voices] {
+                                                    allocator.set_tuning(&tuning);
+                                                }
+                                                keymap.set_tuning(&tuning);
+                                                println!("Tuning: {}", tuning.name());
+                                            },
+                                            Err(err) => eprintln!("Could not load the tuning of the preset: {err}"),
+                                        }
+                                        params.apply(&preset.params);
+                                        matrix.lock().unwrap().apply(&preset.modulation);
+                                        lead.set_oscillator(preset.lead);
+                                        for (kind, saved) in effect_order.iter_mut().zip(preset.effects.iter()) {
+                                            *kind = *saved;
+                                        }
+                                        effect_sender.send(effect_order).unwrap();
+                                        println!("Loaded preset {}", preset.name);
+                                    },
+                                    Err(err) => eprintln!("Could not load preset: {err}"),
+                                }
+                            },
                             _ => ()
                         }
                     }
                     WindowEvent::RedrawRequested {} => {
-                        freq.set_value(midi_hz(state.get_sequencer_value()));
-                        trigger.set_value(state.trigger_envelope());
+                        state.advance_transport();
+                        state.update_sequencer(&mut voices);
+                        state.update_drums(&drums);
+                        state.update_click(&click_gate, &click_pitch);
+                        for event in looper.update(state.beats()) {
+                            voices.play(event);
+                        }
+                        output.check();
+                        if load_history.update() {
+                            load_seconds += 1;
+                            if load_seconds % LOAD_LOG_INTERVAL == 0 {
+                                load_history.print(false);
+                            }
+                        }
 
                         let (width, height) = {
                             let size = state.window.inner_size();
@@ -263,7 +836,70 @@ fn main() {
 
                         let mut buffer = state.surface.buffer_mut().unwrap();
                         //let color = if state.trigger_envelope() > 0.0 { 0x00FF00 } else { 0x101010 };
-                        buffer.fill(0x00FF00);
+                        // The green follows the input level and the background lights up on every beat
+                        analyzer.update();
+                        input_analyzer.update();
+                        let pitch = yin.detect(if pitch_from_output { analyzer.window() } else { input_analyzer.window() });
+                        if audio_to_midi {
+                            for event in note_follower.update(pitch) {
+                                if let (NoteEvent::On(..), Some(pitch)) = (event, pitch) {
+                                    println!("Heard {:.1} Hz: note {} {:+.0} cents, confidence {:.2}", pitch.frequency, pitch.note, pitch.cents, pitch.confidence);
+                                }
+                                voices.play(event);
+                            }
+                        }
+
+                        let analysis = analyzer.analysis();
+                        let green = (0x40 as f64 + input_level.value().min(1.0) * 0xBF as f64) as u32;
+                        let flash = if analysis.beat { 0x606060 } else { 0 };
+                        buffer.fill(flash | green << 8);
+
+                        // Spectrum bars from -60 dB to 0 dB in the color of the note that is sounding,
+                        // without a clear pitch they get redder when the sound is brighter
+                        let red = (analysis.centroid / 5000.0).min(1.0);
+                        let bar_color = match pitch {
+                            Some(pitch) if pitch.confidence > 0.8 => note_color(pitch.note),
+                            _ => ((0x80 as f64 + red * 0x7F as f64) as u32) << 16 | 0x8080,
+                        };
+                        let (width, height) = (width as usize, height as usize);
+                        let bar_width = width / std::cmp::max(analysis.bands.len(), 1);
+                        for (i, band) in analysis.bands.iter().enumerate() {
+                            let level = ((20.0 * band.max(1.0e-6).log10() + 60.0) / 60.0).clamp(0.0, 1.0);
+                            let top = height - (level * height as f64) as usize;
+                            for y in top..height {
+                                buffer[y * width + i * bar_width..y * width + (i + 1) * bar_width].fill(bar_color);
+                            }
+                        }
+
+                        // The grain cloud, left to right is the position in the source and up and down the panning
+                        if grains_track.mute.get() < 0.5 {
+                            for grain in grain_activity.grains() {
+                                // Loud grains would get too big and too bright for the color, and a tiny window too small for any grain
+                                let amplitude = grain.amplitude.clamp(0.0, 1.0);
+                                let size = std::cmp::min(2 + (amplitude * 6.0) as usize, std::cmp::min(width, height));
+                                let x = std::cmp::min((grain.position * width as f64) as usize, width - size);
+                                let y = std::cmp::min(((0.5 - grain.pan * 0.35) * height as f64) as usize, height - size);
+                                let brightness = (0x40 as f64 + amplitude * 0xBF as f64) as u32;
+                                for row in y..y + size {
+                                    buffer[row * width + x..row * width + x + size].fill(brightness << 16 | brightness << 8 | 0xFF);
+                                }
+                            }
+                        }
+
+                        // DSP load along the top, it turns red above 80%
+                        let load = load_meter.load().clamp(0.0, 1.0);
+                        let load_color = if load > 0.8 { 0xFF0000 } else { 0xFFFFFF };
+                        let load_width = (load * width as f64) as usize;
+                        for y in 0..std::cmp::min(height, 4) {
+                            buffer[y * width..y * width + load_width].fill(load_color);
+                        }
+
+                        // Gain reduction of the limiter below it, growing from the right up to 12 dB
+                        shown_reduction = limiter_reduction.value().max(shown_reduction * 0.9);
+                        let reduction_width = ((shown_reduction / 12.0).clamp(0.0, 1.0) * width as f64) as usize;
+                        for y in std::cmp::min(height, 4)..std::cmp::min(height, 8) {
+                            buffer[y * width + width - reduction_width..(y + 1) * width].fill(0xFF8000);
+                        }
                         buffer.present().unwrap();
 
                         state.window.request_redraw();
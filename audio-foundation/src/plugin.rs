@@ -0,0 +1,761 @@
+// The engine as a CLAP plugin: the FM lead, bass, drums and pad through the mixer and the master effects.
+// Every parameter of the registry is a parameter of the plugin, and the state is a preset in the same
+// RON format the app saves, so presets move between the two. Notes on channel 2 play the bass, on 7 the
+// pad and on 10 the drums, the others the lead, like the MIDI channels of the app. While the host plays,
+// the drum pattern follows its transport.
+use std::ffi::{c_char, c_void, CStr};
+use std::ptr::null;
+use std::sync::{Arc, Mutex};
+
+use clap_sys::entry::clap_plugin_entry;
+use clap_sys::events::*;
+use clap_sys::ext::audio_ports::*;
+use clap_sys::ext::note_ports::*;
+use clap_sys::ext::params::*;
+use clap_sys::ext::state::*;
+use clap_sys::factory::plugin_factory::*;
+use clap_sys::fixedpoint::CLAP_BEATTIME_FACTOR;
+use clap_sys::host::clap_host;
+use clap_sys::id::{clap_id, CLAP_INVALID_ID};
+use clap_sys::plugin::*;
+use clap_sys::plugin_features::*;
+use clap_sys::process::*;
+use clap_sys::stream::{clap_istream, clap_ostream};
+use clap_sys::version::CLAP_VERSION;
+use fundsp::hacker::*;
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::engine::drums::{default_pattern, DrumKind, DrumPattern, PATTERN_STEPS};
+use crate::engine::effects::EffectOrder;
+use crate::engine::instruments::{Instruments, Lead};
+use crate::engine::mixer::Mixer;
+use crate::engine::modulation::ModMatrix;
+use crate::engine::params::{Param, ParamRegistry};
+use crate::engine::preset::Preset;
+use crate::engine::tuning::Tuning;
+use crate::engine::voice::NoteEvent;
+
+// The modulation matrix runs once per this many frames, like in the app
+const CONTROL_BLOCK: usize = 64;
+// Tunings a new setup replaces on the audio thread: the three of the voice allocators and the one of the setup
+const RETIRED_PER_SETUP: usize = 4;
+
+// The feature list ends with a null pointer, raw pointers aren't Sync on their own
+struct Features([*const c_char; 4]);
+unsafe impl Sync for Features {}
+
+static FEATURES: Features = Features([
+    CLAP_PLUGIN_FEATURE_INSTRUMENT.as_ptr(),
+    CLAP_PLUGIN_FEATURE_SYNTHESIZER.as_ptr(),
+    CLAP_PLUGIN_FEATURE_STEREO.as_ptr(),
+    null(),
+]);
+
+static DESCRIPTOR: clap_plugin_descriptor = clap_plugin_descriptor {
+    clap_version: CLAP_VERSION,
+    id: c"audio-foundation.synth".as_ptr(),
+    name: c"Audio Foundation".as_ptr(),
+    vendor: c"Audio Foundation".as_ptr(),
+    url: c"".as_ptr(),
+    manual_url: c"".as_ptr(),
+    support_url: c"".as_ptr(),
+    version: c"0.1.0".as_ptr(),
+    description: c"The FM lead, bass, drums and pad of the engine with its effects".as_ptr(),
+    features: FEATURES.0.as_ptr(),
+};
+
+#[allow(non_upper_case_globals)]
+#[unsafe(no_mangle)]
+pub static clap_entry: clap_plugin_entry = clap_plugin_entry {
+    clap_version: CLAP_VERSION,
+    init: Some(entry_init),
+    deinit: Some(entry_deinit),
+    get_factory: Some(entry_get_factory),
+};
+
+unsafe extern "C" fn entry_init(_path: *const c_char) -> bool {
+    true
+}
+
+unsafe extern "C" fn entry_deinit() {}
+
+static FACTORY: clap_plugin_factory = clap_plugin_factory {
+    get_plugin_count: Some(factory_count),
+    get_plugin_descriptor: Some(factory_descriptor),
+    create_plugin: Some(factory_create),
+};
+
+unsafe extern "C" fn entry_get_factory(id: *const c_char) -> *const c_void {
+    if !id.is_null() && unsafe { CStr::from_ptr(id) } == CLAP_PLUGIN_FACTORY_ID {
+        &FACTORY as *const clap_plugin_factory as *const c_void
+    } else {
+        null()
+    }
+}
+
+unsafe extern "C" fn factory_count(_factory: *const clap_plugin_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn factory_descriptor(_factory: *const clap_plugin_factory, index: u32) -> *const clap_plugin_descriptor {
+    if index == 0 { &DESCRIPTOR } else { null() }
+}
+
+unsafe extern "C" fn factory_create(_factory: *const clap_plugin_factory, host: *const clap_host, id: *const c_char) -> *const clap_plugin {
+    if id.is_null() || unsafe { CStr::from_ptr(id) } != unsafe { CStr::from_ptr(DESCRIPTOR.id) } {
+        return null();
+    }
+    // The host owns the plugin from here until it calls destroy
+    let plugin = Box::into_raw(Plugin::new(host));
+    unsafe {
+        (*plugin).clap.plugin_data = plugin as *mut c_void;
+        &(*plugin).clap
+    }
+}
+
+// What the audio thread runs
+struct Engine {
+    mixer: Mixer,
+    instruments: Instruments,
+    pattern: DrumPattern,
+    // The step of the pattern that played last, None while the host isn't playing
+    step: Option<i64>,
+    sample_rate: f64,
+    // Frames until the modulation matrix runs again
+    control: usize,
+    // Tunings that were replaced, they go back to the main thread to be freed
+    retired: Producer<Arc<Tuning>>,
+}
+
+impl Engine {
+    fn play(&mut self, channel: i16, event: NoteEvent) {
+        match channel {
+            1 => self.instruments.bass_voices.play(event),
+            6 => self.instruments.pad_voices.play(event),
+            9 => {
+                if let NoteEvent::On(note, velocity) = event && let Some(kind) = DrumKind::from_note(note) {
+                    self.instruments.drums.hit(kind, velocity);
+                }
+            },
+            _ => self.instruments.voices.play(event),
+        }
+    }
+}
+
+// The parts of a preset that aren't parameters
+#[derive(Clone)]
+struct Setup {
+    tuning: Arc<Tuning>,
+    effects: EffectOrder,
+}
+
+// One instance of the plugin. The host only sees `clap`, whose plugin_data points back here.
+// The main thread functions of CLAP only take &self, so everything the audio thread changes is behind a lock.
+struct Plugin {
+    clap: clap_plugin,
+    host: *const clap_host,
+    params: ParamRegistry,
+    // Parameter ids are hashes of the names, so automation in a project survives new parameters
+    ids: Vec<(clap_id, Param)>,
+    reference: Param,
+    sequencer: Param,
+    matrix: Arc<Mutex<ModMatrix>>,
+    lead: Lead,
+    bpm: Shared<f64>,
+    setup: Mutex<Setup>,
+    // A setup the audio thread hasn't picked up yet, after loading a state
+    pending: Mutex<Option<Setup>>,
+    // The tunings the audio thread is done with, freed when the next state is loaded
+    retired: Mutex<Consumer<Arc<Tuning>>>,
+    engine: Mutex<Engine>,
+}
+
+impl Plugin {
+    fn new(host: *const clap_host) -> Box<Self> {
+        let params = ParamRegistry::new();
+        let bpm = shared(120.0);
+        let reference = params.add("tuning.reference", 20.0, 2000.0, 440.0);
+        let tuning = Arc::new(Tuning::equal(&reference));
+        // The drum pattern plays along with the host unless this is off
+        let sequencer = params.add("sequencer.drums", 0.0, 1.0, 1.0);
+
+        let mut mixer = Mixer::new(&params, &bpm);
+        let instruments = Instruments::new(&params, &mut mixer, &bpm, &tuning);
+        let matrix = Arc::clone(&instruments.matrix);
+        let lead = instruments.lead.clone();
+        let setup = Setup { tuning, effects: mixer.master_mut().order() };
+
+        let ids: Vec<_> = params.params().into_iter().map(|param| (param_id(param.name()), param)).collect();
+        debug_assert_eq!(id_collision(&ids), None, "two parameters have the same id, one would get the automation of the other");
+        // Room for the tunings of two setups, one is drained before the next one is queued
+        let (retired_producer, retired) = RingBuffer::new(2 * RETIRED_PER_SETUP);
+        let engine = Engine { mixer, instruments, pattern: default_pattern(), step: None, sample_rate: DEFAULT_SR, control: 0, retired: retired_producer };
+
+        Box::new(Self {
+            clap: clap_plugin {
+                desc: &DESCRIPTOR,
+                plugin_data: std::ptr::null_mut(),
+                init: Some(plugin_init),
+                destroy: Some(plugin_destroy),
+                activate: Some(plugin_activate),
+                deactivate: Some(plugin_deactivate),
+                start_processing: Some(plugin_start_processing),
+                stop_processing: Some(plugin_stop_processing),
+                reset: Some(plugin_reset),
+                process: Some(plugin_process),
+                get_extension: Some(plugin_get_extension),
+                on_main_thread: Some(plugin_on_main_thread),
+            },
+            host,
+            params,
+            ids,
+            reference,
+            sequencer,
+            matrix,
+            lead,
+            bpm,
+            setup: Mutex::new(setup),
+            pending: Mutex::new(None),
+            retired: Mutex::new(retired),
+            engine: Mutex::new(engine),
+        })
+    }
+
+    fn param(&self, id: clap_id) -> Option<&Param> {
+        self.ids.iter().find(|(param_id, _)| *param_id == id).map(|(_, param)| param)
+    }
+
+    fn preset(&self) -> Preset {
+        let setup = self.setup.lock().unwrap();
+        Preset {
+            name: "plugin".to_string(),
+            params: self.params.values(),
+            modulation: self.matrix.lock().unwrap().settings(),
+            effects: setup.effects.to_vec(),
+            tuning: setup.tuning.settings().clone(),
+            lead: self.lead.oscillator(),
+        }
+    }
+
+    // Like loading a preset in the app
+    fn apply(&self, preset: &Preset) {
+        let mut setup = self.setup.lock().unwrap();
+        while self.retired.lock().unwrap().pop().is_ok() {}
+        // Before the params, the saved reference pitch wins over the keyboard map
+        match Tuning::load(&preset.tuning, &self.reference) {
+            Ok(tuning) => setup.tuning = Arc::new(tuning),
+            Err(err) => eprintln!("Could not load the tuning of the preset: {err}"),
+        }
+        self.params.apply(&preset.params);
+        self.matrix.lock().unwrap().apply(&preset.modulation);
+        self.lead.set_oscillator(preset.lead);
+        for (kind, saved) in setup.effects.iter_mut().zip(preset.effects.iter()) {
+            *kind = *saved;
+        }
+        *self.pending.lock().unwrap() = Some(setup.clone());
+    }
+
+    // Tells the host that the parameters changed without it
+    fn rescan_params(&self) {
+        unsafe {
+            let Some(get_extension) = (*self.host).get_extension else { return };
+            let params = get_extension(self.host, CLAP_EXT_PARAMS.as_ptr()) as *const clap_host_params;
+            if let Some(params) = params.as_ref() && let Some(rescan) = params.rescan {
+                rescan(self.host, CLAP_PARAM_RESCAN_VALUES);
+            }
+        }
+    }
+
+    fn handle_event(&self, engine: &mut Engine, header: &clap_event_header) {
+        if header.space_id != CLAP_CORE_EVENT_SPACE_ID {
+            return;
+        }
+        match header.type_ {
+            CLAP_EVENT_NOTE_ON | CLAP_EVENT_NOTE_OFF => {
+                let note = unsafe { &*(header as *const clap_event_header as *const clap_event_note) };
+                // A key of -1 means all keys, there's no use for that here
+                if let Ok(key) = u8::try_from(note.key) {
+                    let event = if header.type_ == CLAP_EVENT_NOTE_ON { NoteEvent::On(key, note.velocity) } else { NoteEvent::Off(key) };
+                    engine.play(note.channel, event);
+                }
+            },
+            CLAP_EVENT_MIDI => {
+                let midi = unsafe { &*(header as *const clap_event_header as *const clap_event_midi) };
+                let [status, key, velocity] = midi.data;
+                let channel = (status & 0x0F) as i16;
+                match status & 0xF0 {
+                    0x90 if velocity > 0 => engine.play(channel, NoteEvent::On(key, velocity as f64 / 127.0)),
+                    0x80 | 0x90 => engine.play(channel, NoteEvent::Off(key)),
+                    _ => (),
+                }
+            },
+            CLAP_EVENT_PARAM_VALUE => self.handle_param_event(header),
+            _ => (),
+        }
+    }
+
+    fn handle_param_event(&self, header: &clap_event_header) {
+        if header.space_id == CLAP_CORE_EVENT_SPACE_ID && header.type_ == CLAP_EVENT_PARAM_VALUE {
+            let event = unsafe { &*(header as *const clap_event_header as *const clap_event_param_value) };
+            if let Some(param) = self.param(event.param_id) {
+                param.set(event.value);
+            }
+        }
+    }
+
+    // Runs the drum pattern along with the host, one step per sixteenth note
+    fn follow_transport(&self, engine: &mut Engine, transport: Option<&clap_event_transport>, frame: u32) {
+        let playing = transport.filter(|transport| {
+            transport.flags & CLAP_TRANSPORT_IS_PLAYING != 0 && transport.flags & CLAP_TRANSPORT_HAS_BEATS_TIMELINE != 0
+        });
+        let Some(transport) = playing.filter(|_| self.sequencer.value() >= 0.5) else {
+            engine.step = None;
+            return;
+        };
+        let beats = transport.song_pos_beats as f64 / CLAP_BEATTIME_FACTOR as f64 + frame as f64 * self.bpm.value() / 60.0 / engine.sample_rate;
+        let step = (beats * 4.0).floor() as i64;
+        if engine.step != Some(step) {
+            engine.step = Some(step);
+            engine.instruments.drums.play_step(&engine.pattern, step.rem_euclid(PATTERN_STEPS as i64) as usize);
+        }
+    }
+}
+
+// The hash of the parameter name is its id (32 bit FNV-1a)
+fn param_id(name: &str) -> clap_id {
+    let id = name.bytes().fold(0x811c_9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193));
+    if id == CLAP_INVALID_ID { 0 } else { id }
+}
+
+// The names of two parameters with the same id, if there are any
+fn id_collision(ids: &[(clap_id, Param)]) -> Option<(&str, &str)> {
+    let mut names = std::collections::BTreeMap::new();
+    ids.iter().find_map(|(id, param)| names.insert(*id, param.name()).map(|other| (other, param.name())))
+}
+
+// Copies text into a C string buffer of the host, cut off if it doesn't fit
+fn write_str(target: &mut [c_char], text: &str) {
+    let Some(capacity) = target.len().checked_sub(1) else { return };
+    let len = std::cmp::min(text.len(), capacity);
+    for (target, byte) in target.iter_mut().zip(&text.as_bytes()[..len]) {
+        *target = *byte as c_char;
+    }
+    target[len] = 0;
+}
+
+unsafe fn plugin<'a>(clap: *const clap_plugin) -> &'a Plugin {
+    unsafe { &*((*clap).plugin_data as *const Plugin) }
+}
+
+unsafe fn input_events<'a>(list: *const clap_input_events) -> impl Iterator<Item = &'a clap_event_header> {
+    let list = unsafe { list.as_ref() };
+    let count = list.and_then(|events| events.size).map_or(0, |size| unsafe { size(list.unwrap()) });
+    (0..count).filter_map(move |index| unsafe { list?.get?(list?, index).as_ref() })
+}
+
+// Zeros in every channel of every output port
+unsafe fn write_silence(process: &clap_process) {
+    let frames = process.frames_count as usize;
+    let outputs = if process.audio_outputs.is_null() { 0 } else { process.audio_outputs_count as usize };
+    for port in 0..outputs {
+        let output = unsafe { &*process.audio_outputs.add(port) };
+        for channel in 0..output.channel_count as usize {
+            unsafe {
+                if !output.data32.is_null() {
+                    std::slice::from_raw_parts_mut(*output.data32.add(channel), frames).fill(0.0);
+                } else if !output.data64.is_null() {
+                    std::slice::from_raw_parts_mut(*output.data64.add(channel), frames).fill(0.0);
+                }
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn plugin_init(_clap: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_destroy(clap: *const clap_plugin) {
+    drop(unsafe { Box::from_raw((*clap).plugin_data as *mut Plugin) });
+}
+
+unsafe extern "C" fn plugin_activate(clap: *const clap_plugin, sample_rate: f64, _min_frames: u32, _max_frames: u32) -> bool {
+    let plugin = unsafe { plugin(clap) };
+    let mut engine = plugin.engine.lock().unwrap();
+    // Allocates the delay lines, the host doesn't process while activating
+    engine.mixer.set_sample_rate(sample_rate);
+    engine.sample_rate = sample_rate;
+    true
+}
+
+unsafe extern "C" fn plugin_deactivate(_clap: *const clap_plugin) {}
+
+unsafe extern "C" fn plugin_start_processing(_clap: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_stop_processing(_clap: *const clap_plugin) {}
+
+unsafe extern "C" fn plugin_reset(clap: *const clap_plugin) {
+    let plugin = unsafe { plugin(clap) };
+    plugin.engine.lock().unwrap().step = None;
+}
+
+unsafe extern "C" fn plugin_process(clap: *const clap_plugin, process: *const clap_process) -> clap_process_status {
+    let plugin = unsafe { plugin(clap) };
+    let process = unsafe { &*process };
+    let Ok(mut engine) = plugin.engine.try_lock() else {
+        // Only activate and reset take the lock off the audio thread. Hosts may turn off a plugin that
+        // reports an error, so this block is silent and only the parameter changes in it count.
+        unsafe { write_silence(process) };
+        for header in unsafe { input_events(process.in_events) } {
+            plugin.handle_param_event(header);
+        }
+        return CLAP_PROCESS_CONTINUE;
+    };
+    let engine = &mut *engine;
+
+    // A new setup waits until every tuning it replaces fits in the ring, none of them may be freed here
+    if engine.retired.slots() >= RETIRED_PER_SETUP && let Ok(mut pending) = plugin.pending.try_lock() && let Some(Setup { tuning, effects }) = pending.take() {
+        for old in engine.instruments.set_tuning(&tuning) {
+            let _ = engine.retired.push(old);
+        }
+        let _ = engine.retired.push(tuning);
+        engine.mixer.master_mut().set_order(&effects);
+    }
+
+    let transport = unsafe { process.transport.as_ref() };
+    if let Some(transport) = transport && transport.flags & CLAP_TRANSPORT_HAS_TEMPO != 0 {
+        plugin.bpm.set_value(transport.tempo);
+    }
+
+    let frames = process.frames_count as usize;
+    let mut events = unsafe { input_events(process.in_events) }.peekable();
+    let output = if process.audio_outputs_count > 0 { unsafe { process.audio_outputs.as_mut() } } else { None };
+    let Some(output) = output.filter(|output| output.channel_count > 0) else {
+        // Nowhere to play to, but the notes and parameter changes still happen
+        for header in events {
+            plugin.handle_event(engine, header);
+        }
+        return CLAP_PROCESS_CONTINUE;
+    };
+    let channels = std::cmp::min(output.channel_count, 2) as usize;
+
+    let mut pair = [(0.0, 0.0)];
+    for frame in 0..frames {
+        // Events are sorted by time
+        while let Some(header) = events.next_if(|header| header.time as usize <= frame) {
+            plugin.handle_event(engine, header);
+        }
+        plugin.follow_transport(engine, transport, frame as u32);
+        if engine.control == 0 {
+            if let Ok(mut matrix) = plugin.matrix.try_lock() {
+                matrix.tick(CONTROL_BLOCK as f64 / engine.sample_rate);
+            }
+            engine.control = CONTROL_BLOCK;
+        }
+        engine.control -= 1;
+
+        // Tracks sent to other output pairs than the master have nowhere to go
+        engine.mixer.process(&mut pair);
+        let (left, right) = pair[0];
+        for (channel, value) in [left, right].into_iter().enumerate().take(channels) {
+            unsafe {
+                if !output.data32.is_null() {
+                    *(*output.data32.add(channel)).add(frame) = value as f32;
+                } else {
+                    *(*output.data64.add(channel)).add(frame) = value;
+                }
+            }
+        }
+    }
+    for header in events {
+        plugin.handle_event(engine, header);
+    }
+    CLAP_PROCESS_CONTINUE
+}
+
+unsafe extern "C" fn plugin_get_extension(_clap: *const clap_plugin, id: *const c_char) -> *const c_void {
+    if id.is_null() {
+        return null();
+    }
+    let id = unsafe { CStr::from_ptr(id) };
+    if id == CLAP_EXT_PARAMS {
+        &PARAMS as *const clap_plugin_params as *const c_void
+    } else if id == CLAP_EXT_STATE {
+        &STATE as *const clap_plugin_state as *const c_void
+    } else if id == CLAP_EXT_AUDIO_PORTS {
+        &AUDIO_PORTS as *const clap_plugin_audio_ports as *const c_void
+    } else if id == CLAP_EXT_NOTE_PORTS {
+        &NOTE_PORTS as *const clap_plugin_note_ports as *const c_void
+    } else {
+        null()
+    }
+}
+
+unsafe extern "C" fn plugin_on_main_thread(_clap: *const clap_plugin) {}
+
+static PARAMS: clap_plugin_params = clap_plugin_params {
+    count: Some(params_count),
+    get_info: Some(params_get_info),
+    get_value: Some(params_get_value),
+    value_to_text: Some(params_value_to_text),
+    text_to_value: Some(params_text_to_value),
+    flush: Some(params_flush),
+};
+
+unsafe extern "C" fn params_count(clap: *const clap_plugin) -> u32 {
+    unsafe { plugin(clap) }.ids.len() as u32
+}
+
+unsafe extern "C" fn params_get_info(clap: *const clap_plugin, index: u32, info: *mut clap_param_info) -> bool {
+    let plugin = unsafe { plugin(clap) };
+    let (Some((id, param)), Some(info)) = (plugin.ids.get(index as usize), unsafe { info.as_mut() }) else { return false };
+    let (min, max) = param.range();
+    info.id = *id;
+    info.flags = CLAP_PARAM_IS_AUTOMATABLE;
+    info.cookie = std::ptr::null_mut();
+    write_str(&mut info.name, param.name());
+    // "fx.delay.time" is in the module "fx/delay"
+    let module = param.name().rsplit_once('.').map_or("", |(module, _)| module).replace('.', "/");
+    write_str(&mut info.module, &module);
+    info.min_value = min;
+    info.max_value = max;
+    info.default_value = param.default_value();
+    true
+}
+
+unsafe extern "C" fn params_get_value(clap: *const clap_plugin, id: clap_id, value: *mut f64) -> bool {
+    let plugin = unsafe { plugin(clap) };
+    match (plugin.param(id), unsafe { value.as_mut() }) {
+        (Some(param), Some(value)) => {
+            *value = param.get();
+            true
+        },
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn params_value_to_text(clap: *const clap_plugin, id: clap_id, value: f64, buffer: *mut c_char, capacity: u32) -> bool {
+    let plugin = unsafe { plugin(clap) };
+    if plugin.param(id).is_none() || buffer.is_null() {
+        return false;
+    }
+    let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, capacity as usize) };
+    write_str(buffer, &format!("{value:.3}"));
+    true
+}
+
+unsafe extern "C" fn params_text_to_value(clap: *const clap_plugin, id: clap_id, text: *const c_char, value: *mut f64) -> bool {
+    let plugin = unsafe { plugin(clap) };
+    if plugin.param(id).is_none() || text.is_null() {
+        return false;
+    }
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+    match (text.trim().parse::<f64>(), unsafe { value.as_mut() }) {
+        (Ok(parsed), Some(value)) => {
+            *value = parsed;
+            true
+        },
+        _ => false,
+    }
+}
+
+// Parameter changes while the plugin isn't processing
+unsafe extern "C" fn params_flush(clap: *const clap_plugin, events: *const clap_input_events, _out: *const clap_output_events) {
+    let plugin = unsafe { plugin(clap) };
+    for header in unsafe { input_events(events) } {
+        plugin.handle_param_event(header);
+    }
+}
+
+static STATE: clap_plugin_state = clap_plugin_state {
+    save: Some(state_save),
+    load: Some(state_load),
+};
+
+unsafe extern "C" fn state_save(clap: *const clap_plugin, stream: *const clap_ostream) -> bool {
+    let plugin = unsafe { plugin(clap) };
+    let text = match plugin.preset().to_text() {
+        Ok(text) => text,
+        Err(err) => {
+            eprintln!("Could not save the state: {err}");
+            return false;
+        },
+    };
+    let Some(write) = (unsafe { stream.as_ref() }).and_then(|stream| stream.write) else { return false };
+    let mut bytes = text.as_bytes();
+    // The host may take less than everything at once
+    while !bytes.is_empty() {
+        let written = unsafe { write(stream, bytes.as_ptr() as *const c_void, bytes.len() as u64) };
+        if written <= 0 {
+            return false;
+        }
+        bytes = &bytes[written as usize..];
+    }
+    true
+}
+
+unsafe extern "C" fn state_load(clap: *const clap_plugin, stream: *const clap_istream) -> bool {
+    let plugin = unsafe { plugin(clap) };
+    let Some(read) = (unsafe { stream.as_ref() }).and_then(|stream| stream.read) else { return false };
+    let mut bytes = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let count = unsafe { read(stream, buffer.as_mut_ptr() as *mut c_void, buffer.len() as u64) };
+        match count {
+            0 => break,
+            count if count < 0 => return false,
+            count => bytes.extend_from_slice(&buffer[..count as usize]),
+        }
+    }
+    let preset = String::from_utf8(bytes).map_err(Into::into).and_then(|text| Preset::from_text(&text));
+    match preset {
+        Ok(preset) => {
+            plugin.apply(&preset);
+            plugin.rescan_params();
+            true
+        },
+        Err(err) => {
+            eprintln!("Could not load the state: {err}");
+            false
+        },
+    }
+}
+
+static AUDIO_PORTS: clap_plugin_audio_ports = clap_plugin_audio_ports {
+    count: Some(audio_ports_count),
+    get: Some(audio_ports_get),
+};
+
+// No inputs, one stereo output
+unsafe extern "C" fn audio_ports_count(_clap: *const clap_plugin, is_input: bool) -> u32 {
+    if is_input { 0 } else { 1 }
+}
+
+unsafe extern "C" fn audio_ports_get(_clap: *const clap_plugin, index: u32, is_input: bool, info: *mut clap_audio_port_info) -> bool {
+    let Some(info) = (unsafe { info.as_mut() }).filter(|_| index == 0 && !is_input) else { return false };
+    info.id = 0;
+    write_str(&mut info.name, "Main");
+    info.flags = CLAP_AUDIO_PORT_IS_MAIN;
+    info.channel_count = 2;
+    info.port_type = CLAP_PORT_STEREO.as_ptr();
+    info.in_place_pair = CLAP_INVALID_ID;
+    true
+}
+
+static NOTE_PORTS: clap_plugin_note_ports = clap_plugin_note_ports {
+    count: Some(note_ports_count),
+    get: Some(note_ports_get),
+};
+
+unsafe extern "C" fn note_ports_count(_clap: *const clap_plugin, is_input: bool) -> u32 {
+    if is_input { 1 } else { 0 }
+}
+
+unsafe extern "C" fn note_ports_get(_clap: *const clap_plugin, index: u32, is_input: bool, info: *mut clap_note_port_info) -> bool {
+    let Some(info) = (unsafe { info.as_mut() }).filter(|_| index == 0 && is_input) else { return false };
+    info.id = 0;
+    info.supported_dialects = CLAP_NOTE_DIALECT_CLAP | CLAP_NOTE_DIALECT_MIDI;
+    info.preferred_dialect = CLAP_NOTE_DIALECT_CLAP;
+    write_str(&mut info.name, "Notes");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::instruments::LeadOscillator;
+
+    static HOST: clap_host = clap_host {
+        clap_version: CLAP_VERSION,
+        host_data: std::ptr::null_mut(),
+        name: c"test".as_ptr(),
+        vendor: c"".as_ptr(),
+        url: c"".as_ptr(),
+        version: c"".as_ptr(),
+        get_extension: None,
+        request_restart: None,
+        request_process: None,
+        request_callback: None,
+    };
+
+    // Like factory_create, without handing the plugin over
+    fn new_plugin() -> Box<Plugin> {
+        let mut plugin = Plugin::new(&HOST);
+        plugin.clap.plugin_data = &mut *plugin as *mut Plugin as *mut c_void;
+        plugin
+    }
+
+    #[test]
+    fn ids_are_fnv_hashes() {
+        assert_eq!(param_id(""), 0x811c_9dc5);
+        assert_eq!(param_id("a"), 0xe40c_292c);
+        assert_eq!(param_id("foobar"), 0xbf9c_f968);
+        // Automation in saved projects depends on these staying the same
+        assert_eq!(param_id("master.volume"), param_id("master.volume"));
+        assert_ne!(param_id("master.volume"), param_id("master.volumf"));
+    }
+
+    #[test]
+    fn every_param_has_its_own_id() {
+        let plugin = new_plugin();
+        assert_eq!(id_collision(&plugin.ids), None);
+        let ids = ["a", "b", "a"].map(|name| (param_id(name), Param::new(name, 0.0, 1.0, 0.0)));
+        assert_eq!(id_collision(&ids), Some(("a", "a")));
+    }
+
+    #[test]
+    fn write_str_cuts_off_and_terminates() {
+        let text = |buffer: &[c_char]| unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap().to_string();
+        let mut buffer = [1 as c_char; 5];
+        write_str(&mut buffer, "hi");
+        assert_eq!(text(&buffer), "hi");
+        write_str(&mut buffer, "hello world");
+        assert_eq!(text(&buffer), "hell");
+        write_str(&mut buffer[..1], "hello");
+        assert_eq!(buffer[0], 0);
+        // Nothing fits, not even the terminator
+        write_str(&mut [], "hello");
+    }
+
+    // A stream that takes and gives at most 100 bytes at a time, like a host might
+    unsafe extern "C" fn write(stream: *const clap_ostream, buffer: *const c_void, size: u64) -> i64 {
+        let bytes = unsafe { &mut *((*stream).ctx as *mut Vec<u8>) };
+        let size = std::cmp::min(size, 100) as usize;
+        bytes.extend_from_slice(unsafe { std::slice::from_raw_parts(buffer as *const u8, size) });
+        size as i64
+    }
+
+    unsafe extern "C" fn read(stream: *const clap_istream, buffer: *mut c_void, size: u64) -> i64 {
+        let bytes = unsafe { &mut *((*stream).ctx as *mut Vec<u8>) };
+        let size = std::cmp::min(std::cmp::min(size, 100) as usize, bytes.len());
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), buffer as *mut u8, size) };
+        bytes.drain(..size);
+        size as i64
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let saved = new_plugin();
+        saved.params.get("tuning.reference").unwrap().set(432.0);
+        saved.params.get("master.volume").unwrap().set(0.7);
+        saved.lead.set_oscillator(LeadOscillator::Unison);
+        let mut bytes: Vec<u8> = Vec::new();
+        let output = clap_ostream { ctx: &mut bytes as *mut Vec<u8> as *mut c_void, write: Some(write) };
+        assert!(unsafe { state_save(&saved.clap, &output) });
+
+        let loaded = new_plugin();
+        let input = clap_istream { ctx: &mut bytes as *mut Vec<u8> as *mut c_void, read: Some(read) };
+        assert!(unsafe { state_load(&loaded.clap, &input) });
+        assert_eq!(loaded.params.values(), saved.params.values());
+        // The saved reference wins over the one of the keyboard map
+        assert_eq!(loaded.reference.get(), 432.0);
+        assert_eq!(loaded.lead.oscillator(), LeadOscillator::Unison);
+        assert!(loaded.pending.lock().unwrap().is_some());
+
+        let mut garbage = b"not a preset".to_vec();
+        let input = clap_istream { ctx: &mut garbage as *mut Vec<u8> as *mut c_void, read: Some(read) };
+        assert!(!unsafe { state_load(&loaded.clap, &input) });
+    }
+}